@@ -0,0 +1,440 @@
+//! Error correcting codes that spread data over the slices of a set.
+//!
+//! A codec turns every block of data into an equal share for each slice, such that the block
+//! can still be decoded with some slices lost and bit errors in others. Golay (24,12,8) doubles
+//! the data and survives the loss of up to a third of the slices; Reed-Solomon over GF(256)
+//! takes any number of parity slices, so it can cost as little as one extra slice.
+
+use std::fmt;
+
+use crate::cpu;
+use crate::error::PhnxError;
+use crate::golay::{GolayCode, GolayStats};
+use crate::reed_solomon::ReedSolomon;
+
+/// Codec in the slice header: Golay (24,12,8), every codeword spread evenly over the slices
+pub const CODEC_GOLAY_24_12: u8 = 1;
+
+/// Codec in the slice header: Reed-Solomon over GF(256), one byte of every codeword in each
+/// slice, the parity slices last
+pub const CODEC_REED_SOLOMON: u8 = 2;
+
+/// Numbers of slices a Golay set can have: those that divide the 24 bits of a codeword evenly
+/// and leave something to lose. Versions 1 to 3 always have 8.
+pub const SLICE_COUNTS: [usize; 5] = [4, 6, 8, 12, 24];
+
+/// Slices in a set unless asked otherwise
+pub const DEFAULT_SLICE_COUNT: usize = 8;
+
+/// Largest set there can be, whose last slice is X
+pub const MAX_SLICE_COUNT: usize = 24;
+
+/// How many slices of a Golay set may be lost. Each one holds 24 / slice_count bits of every
+/// codeword, and a codeword with up to 7 known erasures and no other errors is recovered.
+pub fn max_missing_slices(slice_count: usize) -> usize {
+    7 / (24 / slice_count)
+}
+
+/// Error correcting code behind the slices of a set. Every block of block_size() bytes of
+/// data is encoded into share() bytes for each slice, which follow each other in slice order.
+pub trait Codec {
+    /// Data bytes in a block
+    fn block_size(&self) -> usize;
+
+    /// Bytes that each slice takes of an encoded block
+    fn share(&self) -> usize;
+
+    fn encode_block(&self, data: &[u8], encoded: &mut [u8]);
+
+    /// Decodes a block, ignoring the bytes of slices marked missing. Sets the bits it
+    /// corrected in errors, laid out as the encoded block. Returns false if the block is
+    /// uncorrectable, in which case some of data is wrong.
+    fn decode_block(
+        &mut self,
+        encoded: &[u8],
+        missing: &[bool],
+        data: &mut [u8],
+        errors: &mut [u8],
+    ) -> bool;
+
+    fn stats(&self) -> &GolayStats;
+}
+
+/// Golay (24,12,8), 12 bytes at a time: the 8 codewords of a block are spread bit by bit over
+/// 24 bytes, and the slices take consecutive bytes of those
+pub struct GolayCodec {
+    gc: GolayCode,
+    slice_count: usize,
+}
+
+impl GolayCodec {
+    /// Slice count must be one of SLICE_COUNTS
+    pub fn new(slice_count: usize) -> Self {
+        GolayCodec {
+            gc: GolayCode::new(),
+            slice_count,
+        }
+    }
+}
+
+impl Codec for GolayCodec {
+    fn block_size(&self) -> usize {
+        12
+    }
+
+    fn share(&self) -> usize {
+        24 / self.slice_count
+    }
+
+    fn encode_block(&self, data: &[u8], encoded: &mut [u8]) {
+        encoded.copy_from_slice(&golay_encode_block(data.try_into().unwrap(), &self.gc));
+    }
+
+    fn decode_block(
+        &mut self,
+        encoded: &[u8],
+        missing: &[bool],
+        data: &mut [u8],
+        errors: &mut [u8],
+    ) -> bool {
+        // Bits of a missing slice are known to be lost, so the decoder can ignore them
+        let share = self.share();
+        let mut erasures = 0u32;
+        for (i, &lost) in missing.iter().enumerate().take(self.slice_count) {
+            if lost {
+                erasures |= ((1 << share) - 1) << (i * share);
+            }
+        }
+
+        let uncorrectable = self.gc.stats.uncorrectable_codewords;
+        let mut block_errors = [0u8; 24];
+        let twelvebytes = golay_decode_block(
+            encoded.try_into().unwrap(),
+            erasures,
+            &mut self.gc,
+            &mut block_errors,
+        );
+        data.copy_from_slice(&twelvebytes);
+        errors.copy_from_slice(&block_errors);
+        self.gc.stats.uncorrectable_codewords == uncorrectable
+    }
+
+    fn stats(&self) -> &GolayStats {
+        &self.gc.stats
+    }
+}
+
+impl Codec for ReedSolomon {
+    fn block_size(&self) -> usize {
+        self.data_slices()
+    }
+
+    fn share(&self) -> usize {
+        1
+    }
+
+    fn encode_block(&self, data: &[u8], encoded: &mut [u8]) {
+        self.encode(data, encoded);
+    }
+
+    fn decode_block(
+        &mut self,
+        encoded: &[u8],
+        missing: &[bool],
+        data: &mut [u8],
+        errors: &mut [u8],
+    ) -> bool {
+        let mut codeword = encoded.to_vec();
+        let decoded = self.decode(&mut codeword, missing, errors);
+        data.copy_from_slice(&codeword[..data.len()]);
+        decoded
+    }
+
+    fn stats(&self) -> &GolayStats {
+        &self.stats
+    }
+}
+
+/// Which codec a set uses and how it is split, as recorded in the slice headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecParams {
+    /// CODEC_GOLAY_24_12 or CODEC_REED_SOLOMON
+    pub codec: u8,
+    pub slice_count: u8,
+    /// Reed-Solomon slices that hold parity, 0 for Golay
+    pub parity_slices: u8,
+}
+
+impl Default for CodecParams {
+    /// Golay over DEFAULT_SLICE_COUNT slices
+    fn default() -> Self {
+        CodecParams {
+            codec: CODEC_GOLAY_24_12,
+            slice_count: DEFAULT_SLICE_COUNT as u8,
+            parity_slices: 0,
+        }
+    }
+}
+
+impl CodecParams {
+    /// Golay over slice_count slices, one of SLICE_COUNTS
+    pub fn golay(slice_count: usize) -> Result<Self, PhnxError> {
+        if !SLICE_COUNTS.contains(&slice_count) {
+            return Err(PhnxError::Format(format!(
+                "Cannot split into {} slices, only into {:?}",
+                slice_count, SLICE_COUNTS
+            )));
+        }
+        Ok(CodecParams {
+            codec: CODEC_GOLAY_24_12,
+            slice_count: slice_count as u8,
+            parity_slices: 0,
+        })
+    }
+
+    /// Reed-Solomon with data_slices + parity_slices slices, at most MAX_SLICE_COUNT
+    pub fn reed_solomon(data_slices: usize, parity_slices: usize) -> Result<Self, PhnxError> {
+        if data_slices == 0 || parity_slices == 0 || data_slices + parity_slices > MAX_SLICE_COUNT
+        {
+            return Err(PhnxError::Format(format!(
+                "Cannot split into {} data and {} parity slices, need at least one of each \
+                 and at most {} in all",
+                data_slices, parity_slices, MAX_SLICE_COUNT
+            )));
+        }
+        Ok(CodecParams {
+            codec: CODEC_REED_SOLOMON,
+            slice_count: (data_slices + parity_slices) as u8,
+            parity_slices: parity_slices as u8,
+        })
+    }
+
+    /// Fails unless this implementation can decode the set, such as with values read from a
+    /// slice header
+    pub fn check(&self) -> Result<(), PhnxError> {
+        let slice_count = self.slice_count as usize;
+        let parity_slices = self.parity_slices as usize;
+        let checked = match self.codec {
+            CODEC_GOLAY_24_12 if parity_slices == 0 => CodecParams::golay(slice_count),
+            CODEC_REED_SOLOMON if parity_slices < slice_count => {
+                CodecParams::reed_solomon(slice_count - parity_slices, parity_slices)
+            }
+            _ => Err(PhnxError::Format(format!(
+                "Unsupported codec {} with {} parity slices",
+                self.codec, self.parity_slices
+            ))),
+        };
+        checked.map(|_| ())
+    }
+
+    pub fn slice_count(&self) -> usize {
+        self.slice_count as usize
+    }
+
+    /// How many slices of the set may be lost
+    pub fn max_missing(&self) -> usize {
+        match self.codec {
+            CODEC_REED_SOLOMON => self.parity_slices as usize,
+            _ => max_missing_slices(self.slice_count()),
+        }
+    }
+
+    /// Data bytes in a block
+    pub fn block_size(&self) -> usize {
+        match self.codec {
+            CODEC_REED_SOLOMON => self.slice_count() - self.parity_slices as usize,
+            _ => 12,
+        }
+    }
+
+    /// Bytes that each slice takes of an encoded block
+    pub fn share(&self) -> usize {
+        match self.codec {
+            CODEC_REED_SOLOMON => 1,
+            _ => 24 / self.slice_count(),
+        }
+    }
+
+    /// Bytes that each slice takes for data_size bytes of data, padded to whole blocks
+    pub fn slice_bytes(&self, data_size: usize) -> usize {
+        data_size.div_ceil(self.block_size()) * self.share()
+    }
+
+    /// Fresh codec with zero statistics
+    pub fn codec(&self) -> Box<dyn Codec> {
+        match self.codec {
+            CODEC_REED_SOLOMON => Box::new(ReedSolomon::new(
+                self.block_size(),
+                self.parity_slices as usize,
+            )),
+            _ => Box::new(GolayCodec::new(self.slice_count())),
+        }
+    }
+}
+
+impl fmt::Display for CodecParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.codec {
+            CODEC_REED_SOLOMON => write!(
+                f,
+                "Reed-Solomon with {} data and {} parity slices",
+                self.block_size(),
+                self.parity_slices
+            ),
+            _ => write!(f, "Golay (24,12,8) over {} slices", self.slice_count),
+        }
+    }
+}
+
+/// Decodes 8 codewords into 12 bytes. Byte j of the 24 holds bit j of every codeword, slices
+/// take consecutive bytes. Uses BMI2 if the CPU has it.
+/// Bits corrected in each of the 24 input bytes are set in errors.
+fn golay_decode_block(
+    eighttriplets: &[u8; 24],
+    erasures: u32,
+    gc: &mut GolayCode,
+    errors: &mut [u8; 24],
+) -> [u8; 12] {
+    #[cfg(target_arch = "x86_64")]
+    if cpu::features().bmi2 {
+        // Only called once BMI2 has been detected
+        return unsafe { golay_decode_block_bmi2(eighttriplets, erasures, gc, errors) };
+    }
+
+    let mut twelvebytes = [0u8; 12];
+    for i in 0..8 {
+        let mut codeword = 0u32;
+        for k in 0..8 {
+            for t in 0..3 {
+                if eighttriplets[k * 3 + t] & (1 << i) != 0 {
+                    codeword |= 1 << (k * 3 + t);
+                }
+            }
+        }
+
+        let x = decode_codeword(codeword, i, erasures, gc, errors);
+        for (j, byte) in twelvebytes.iter_mut().enumerate() {
+            if x & (1 << j) != 0 {
+                *byte |= 1 << i;
+            }
+        }
+    }
+    twelvebytes
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "bmi2")]
+unsafe fn golay_decode_block_bmi2(
+    eighttriplets: &[u8; 24],
+    erasures: u32,
+    gc: &mut GolayCode,
+    errors: &mut [u8; 24],
+) -> [u8; 12] {
+    use std::arch::x86_64::{_pdep_u64, _pext_u64};
+
+    let qwords = [
+        u64::from_le_bytes(eighttriplets[0..8].try_into().unwrap()),
+        u64::from_le_bytes(eighttriplets[8..16].try_into().unwrap()),
+        u64::from_le_bytes(eighttriplets[16..24].try_into().unwrap()),
+    ];
+    let mut twelvebytes_q = [0u64; 2];
+    for i in 0..8 {
+        let mask: u64 = 0x0101010101010101u64 << i;
+        let extracted_lo = _pext_u64(qwords[0], mask);
+        let extracted_mid = _pext_u64(qwords[1], mask);
+        let extracted_hi = _pext_u64(qwords[2], mask);
+        let codeword = (extracted_lo | (extracted_mid << 8) | (extracted_hi << 16)) as u32;
+
+        let x = decode_codeword(codeword, i, erasures, gc, errors);
+        let halfmask: u64 = mask & 0xffffffff;
+        twelvebytes_q[0] |= _pdep_u64(x as u64, mask);
+        twelvebytes_q[1] |= _pdep_u64((x >> 8) as u64, halfmask);
+    }
+
+    let mut twelvebytes = [0u8; 12];
+    twelvebytes[..8].copy_from_slice(&twelvebytes_q[0].to_le_bytes());
+    twelvebytes[8..12].copy_from_slice(&twelvebytes_q[1].to_le_bytes()[..4]);
+    twelvebytes
+}
+
+/// Decodes codeword i of a block, setting bit i of the input bytes whose bits it corrected
+#[inline(always)]
+fn decode_codeword(
+    codeword: u32,
+    i: usize,
+    erasures: u32,
+    gc: &mut GolayCode,
+    errors: &mut [u8; 24],
+) -> i32 {
+    let (x, pattern) = gc.decode_with_error_pattern(codeword, erasures);
+    if pattern != 0 {
+        for (j, error) in errors.iter_mut().enumerate() {
+            if pattern & (1 << j) != 0 {
+                *error |= 1 << i;
+            }
+        }
+    }
+    x
+}
+
+/// Encodes 12 bytes into 8 codewords laid out as golay_decode_block() takes them.
+/// Uses BMI2 if the CPU has it.
+fn golay_encode_block(twelvebytes: &[u8; 12], gc: &GolayCode) -> [u8; 24] {
+    #[cfg(target_arch = "x86_64")]
+    if cpu::features().bmi2 {
+        // Only called once BMI2 has been detected
+        return unsafe { golay_encode_block_bmi2(twelvebytes, gc) };
+    }
+
+    let mut eighttriplets = [0u8; 24];
+    for i in 0..8 {
+        let mut x = 0u32;
+        for (j, byte) in twelvebytes.iter().enumerate() {
+            if byte & (1 << i) != 0 {
+                x |= 1 << j;
+            }
+        }
+
+        let codeword = gc.encode(x);
+        for k in 0..8 {
+            for t in 0..3 {
+                if codeword & (1 << (k * 3 + t)) != 0 {
+                    eighttriplets[k * 3 + t] |= 1 << i;
+                }
+            }
+        }
+    }
+    eighttriplets
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "bmi2")]
+unsafe fn golay_encode_block_bmi2(twelvebytes: &[u8; 12], gc: &GolayCode) -> [u8; 24] {
+    use std::arch::x86_64::{_pdep_u64, _pext_u64};
+
+    let mut high = [0u8; 8];
+    high[..4].copy_from_slice(&twelvebytes[8..12]);
+    let twelvebytes_q = [
+        u64::from_le_bytes(twelvebytes[0..8].try_into().unwrap()),
+        u64::from_le_bytes(high),
+    ];
+    let mut eighttriplets_q = [0u64; 3];
+    for i in 0..8 {
+        let mask: u64 = 0x0101010101010101u64 << i;
+        let halfmask: u64 = mask & 0xffffffff;
+        let bits0to7 = _pext_u64(twelvebytes_q[0], mask);
+        let bits8to11 = _pext_u64(twelvebytes_q[1], halfmask);
+        let x = (bits0to7 | (bits8to11 << 8)) as u32;
+
+        let codeword = gc.encode(x);
+        eighttriplets_q[0] |= _pdep_u64((codeword & 0xff) as u64, mask);
+        eighttriplets_q[1] |= _pdep_u64(((codeword >> 8) & 0xff) as u64, mask);
+        eighttriplets_q[2] |= _pdep_u64(((codeword >> 16) & 0xff) as u64, mask);
+    }
+
+    let mut eighttriplets = [0u8; 24];
+    eighttriplets[0..8].copy_from_slice(&eighttriplets_q[0].to_le_bytes());
+    eighttriplets[8..16].copy_from_slice(&eighttriplets_q[1].to_le_bytes());
+    eighttriplets[16..24].copy_from_slice(&eighttriplets_q[2].to_le_bytes());
+    eighttriplets
+}
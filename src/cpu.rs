@@ -0,0 +1,46 @@
+//! Instruction set extensions found at run time.
+//!
+//! The same binary runs on any x86 host and picks the fastest code the host supports: AVX2
+//! for the Speck keystream, SSE4.2 for CRC32C, and BMI2 for spreading Golay codewords over
+//! slices. Other architectures always use the portable code.
+
+use std::sync::OnceLock;
+
+/// Environment variable that turns every faster code path off
+pub const PORTABLE_VAR: &str = "PHNX_PORTABLE";
+
+/// Extensions that have a faster code path
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub sse42: bool,
+    pub avx2: bool,
+    pub bmi2: bool,
+}
+
+impl CpuFeatures {
+    fn detect() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            CpuFeatures {
+                sse42: is_x86_feature_detected!("sse4.2"),
+                avx2: is_x86_feature_detected!("avx2"),
+                // pext and pdep on 64-bit operands only exist in 64-bit mode
+                bmi2: cfg!(target_arch = "x86_64") && is_x86_feature_detected!("bmi2"),
+            }
+        }
+
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        CpuFeatures::default()
+    }
+}
+
+/// Detects extensions on the first call, later calls return the same. None are used if
+/// PHNX_PORTABLE is set in the environment, so that the portable code can be compared with
+/// the faster paths on the same host.
+pub fn features() -> CpuFeatures {
+    static FEATURES: OnceLock<CpuFeatures> = OnceLock::new();
+    *FEATURES.get_or_init(|| match std::env::var_os(PORTABLE_VAR) {
+        Some(_) => CpuFeatures::default(),
+        None => CpuFeatures::detect(),
+    })
+}
@@ -1,4 +1,5 @@
-#[allow(dead_code)]
+use crate::cpu;
+
 const CRC32C_TABLE: [u32; 256] = {
     let mut table = [0u32; 256];
     let mut i = 0u32;
@@ -21,39 +22,112 @@ const CRC32C_TABLE: [u32; 256] = {
 
 pub struct Crc32c {
     value: u32,
+    /// Whether the CPU has SSE4.2, looked up once rather than for every update
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    sse42: bool,
+}
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Crc32c {
     pub fn new() -> Self {
-        Crc32c { value: !0u32 }
+        Crc32c {
+            value: !0u32,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            sse42: cpu::features().sse42,
+        }
     }
 
     #[inline]
     pub fn update(&mut self, byte: u8) {
-        #[cfg(target_feature = "sse4.2")]
-        {
-            #[cfg(target_arch = "x86_64")]
-            use std::arch::x86_64::_mm_crc32_u8;
-            #[cfg(target_arch = "x86")]
-            use std::arch::x86::_mm_crc32_u8;
-            self.value = unsafe { _mm_crc32_u8(self.value, byte) };
-            return;
-        }
-
-        #[cfg(not(target_feature = "sse4.2"))]
-        {
-            self.value = CRC32C_TABLE[((self.value ^ byte as u32) & 0xff) as usize]
-                ^ (self.value >> 8);
-        }
+        self.update_slice(&[byte]);
     }
 
+    /// Uses the SSE4.2 crc32 instruction if the CPU has it
     pub fn update_slice(&mut self, data: &[u8]) {
-        for &b in data {
-            self.update(b);
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if self.sse42 {
+            // Only set once SSE4.2 has been detected
+            self.value = unsafe { update_sse42(self.value, data) };
+            return;
         }
+
+        self.value = update_portable(self.value, data);
     }
 
     pub fn finalize(&self) -> u32 {
         !self.value
     }
 }
+
+fn update_portable(mut value: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        value = CRC32C_TABLE[((value ^ b as u32) & 0xff) as usize] ^ (value >> 8);
+    }
+    value
+}
+
+/// Eight bytes per instruction, four on 32-bit x86, then the bytes left over one at a time
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.2")]
+unsafe fn update_sse42(value: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::{_mm_crc32_u32, _mm_crc32_u8};
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    #[cfg(target_arch = "x86_64")]
+    let (mut value, tail) = {
+        let mut words = data.chunks_exact(8);
+        let mut wide = value as u64;
+        for w in &mut words {
+            wide = _mm_crc32_u64(wide, u64::from_le_bytes(w.try_into().unwrap()));
+        }
+        (wide as u32, words.remainder())
+    };
+    #[cfg(target_arch = "x86")]
+    let (mut value, tail) = {
+        let mut words = data.chunks_exact(4);
+        let mut value = value;
+        for w in &mut words {
+            value = _mm_crc32_u32(value, u32::from_le_bytes(w.try_into().unwrap()));
+        }
+        (value, words.remainder())
+    };
+
+    for &b in tail {
+        value = _mm_crc32_u8(value, b);
+    }
+    value
+}
+
+pub fn self_test() -> bool {
+    // Check value from RFC 3720, the same with and without SSE4.2
+    let mut crc32c = Crc32c::new();
+    crc32c.update_slice(b"123456789");
+    if crc32c.finalize() != 0xe3069283 {
+        eprintln!("Crc32c self-test failed");
+        eprintln!("Expected 0xe3069283, observed 0x{:x}", crc32c.finalize());
+        return false;
+    }
+
+    // Every length and alignment of the word loop and its tail against the table
+    let data: Vec<u8> = (0..100u32).map(|i| (i * 167 + 13) as u8).collect();
+    for start in 0..8 {
+        for end in start..data.len() {
+            let mut crc32c = Crc32c::new();
+            crc32c.update_slice(&data[start..end]);
+            let expected = update_portable(!0, &data[start..end]);
+            if crc32c.value != expected {
+                eprintln!("Crc32c self-test failed for bytes {}..{}", start, end);
+                eprintln!("Expected 0x{:x}, observed 0x{:x}", expected, crc32c.value);
+                return false;
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::io;
+
+use crate::slices::Report;
+
+pub const PHNX_OK: i32 = 0;
+pub const PHNX_IO_ERROR: i32 = 1;
+pub const PHNX_WRONG_PASSWORD: i32 = 2;
+pub const PHNX_UNCORRECTABLE_ERROR: i32 = 3;
+pub const PHNX_FORMAT_ERROR: i32 = 4;
+pub const PHNX_SELF_TEST_FAILED: i32 = 5;
+pub const PHNX_AUTH_FAILED: i32 = 6;
+
+#[derive(Debug)]
+pub enum PhnxError {
+    /// Reading, writing, or seeking failed; the string says what was being done
+    Io(String, io::Error),
+    /// Password check in the suffix failed
+    WrongPassword,
+    /// Damage exceeds what error correction can undo
+    Uncorrectable(String),
+    /// Decoding finished, but some plaintext byte ranges could not be recovered; the report
+    /// lists them
+    Damaged(Box<Report>),
+    /// Input is not in the expected format, or its checksum does not match
+    Format(String),
+    /// MAC does not match, so the slices were modified or do not belong together. The report
+    /// lists chunks that failed; it is empty if the trailer did.
+    AuthFailed(Box<Report>),
+}
+
+impl PhnxError {
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        PhnxError::Io(context.into(), source)
+    }
+
+    /// Process exit code, one of PHNX_* constants
+    pub fn code(&self) -> i32 {
+        match self {
+            PhnxError::Io(..) => PHNX_IO_ERROR,
+            PhnxError::WrongPassword => PHNX_WRONG_PASSWORD,
+            PhnxError::Uncorrectable(_) | PhnxError::Damaged(_) => PHNX_UNCORRECTABLE_ERROR,
+            PhnxError::Format(_) => PHNX_FORMAT_ERROR,
+            PhnxError::AuthFailed(_) => PHNX_AUTH_FAILED,
+        }
+    }
+}
+
+impl fmt::Display for PhnxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhnxError::Io(context, source) => write!(f, "{}: {}", context, source),
+            PhnxError::WrongPassword => write!(f, "CRC mismatch, wrong password?"),
+            PhnxError::Uncorrectable(msg) => write!(f, "{}", msg),
+            PhnxError::Damaged(report) => write!(
+                f,
+                "{} bytes in {} ranges could not be recovered",
+                report.damaged.iter().map(|r| r.end - r.start).sum::<u64>(),
+                report.damaged.len()
+            ),
+            PhnxError::Format(msg) => write!(f, "{}", msg),
+            PhnxError::AuthFailed(report) if !report.unauthenticated.is_empty() => write!(
+                f,
+                "{} bytes in {} ranges failed authentication",
+                report.unauthenticated.iter().map(|r| r.end - r.start).sum::<u64>(),
+                report.unauthenticated.len()
+            ),
+            PhnxError::AuthFailed(_) => {
+                write!(f, "Authentication failed, slices were modified or mixed up")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PhnxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PhnxError::Io(_, source) => Some(source),
+            _ => None,
+        }
+    }
+}
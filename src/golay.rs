@@ -1,28 +1,65 @@
+use std::fmt;
+
 const GOLAY_MATRIX: [u32; 12] = [
     0x9f1, 0x4fa, 0x27d, 0x93e, 0xc9d, 0xe4e,
     0xf25, 0xf92, 0x7c9, 0x3e6, 0x557, 0xaab,
 ];
 
+/// Decoding statistics. Counters are 64-bit, since eight codewords are decoded per 12 bytes.
+/// Statistics of chunks decoded separately can be merged. Reed-Solomon codewords are counted
+/// the same way, without corrected_bits.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GolayStats {
+    pub processed_codewords: u64,
+    pub corrected_codewords: u64,
+    pub uncorrectable_codewords: u64,
+    /// How many times each of the 24 codeword bits was corrected
+    pub corrected_bits: [u64; 24],
+}
+
+impl GolayStats {
+    pub fn merge(&mut self, other: &GolayStats) {
+        self.processed_codewords += other.processed_codewords;
+        self.corrected_codewords += other.corrected_codewords;
+        self.uncorrectable_codewords += other.uncorrectable_codewords;
+        for j in 0..24 {
+            self.corrected_bits[j] += other.corrected_bits[j];
+        }
+    }
+}
+
+impl fmt::Display for GolayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} codewords, corrected {}, {} uncorrectable",
+            self.processed_codewords, self.corrected_codewords, self.uncorrectable_codewords
+        )
+    }
+}
+
 pub struct GolayCode {
-    pub processed_codewords: i32,
-    pub corrected_codewords: i32,
-    pub uncorrectable_codewords: i32,
+    pub stats: GolayStats,
+}
+
+impl Default for GolayCode {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GolayCode {
     pub fn new() -> Self {
         GolayCode {
-            processed_codewords: 0,
-            corrected_codewords: 0,
-            uncorrectable_codewords: 0,
+            stats: GolayStats::default(),
         }
     }
 
     #[inline]
     fn checksum_bits(x: u32) -> u32 {
         let mut y = 0u32;
-        for i in 0..12 {
-            y = (y << 1) | ((x & GOLAY_MATRIX[i]).count_ones() & 1);
+        for row in GOLAY_MATRIX {
+            y = (y << 1) | ((x & row).count_ones() & 1);
         }
         y
     }
@@ -36,48 +73,93 @@ impl GolayCode {
     /// Takes a 24 bit codeword, returns decoded 12 bits.
     /// On unrecoverable error, returns -1.
     pub fn decode(&mut self, x: u32) -> i32 {
-        self.processed_codewords += 1;
+        self.decode_with_error_pattern(x, 0).0
+    }
+
+    /// Takes a 24 bit codeword and a mask of bits known to be lost (erasures),
+    /// returns decoded 12 bits. Erased bits are ignored, so up to 7 of them can be
+    /// filled in, as long as twice the number of other errors plus the number of
+    /// erasures stays below 8. On unrecoverable error, returns -1.
+    pub fn decode_with_erasures(&mut self, x: u32, erasures: u32) -> i32 {
+        self.decode_with_error_pattern(x, erasures).0
+    }
+
+    /// Same as decode_with_erasures(), also returns the error pattern, i.e. the bits that
+    /// had to be flipped to get a valid codeword. Erased bits are not part of the pattern.
+    /// On unrecoverable error, returns -1 and an empty pattern.
+    pub fn decode_with_error_pattern(&mut self, x: u32, erasures: u32) -> (i32, u32) {
+        self.stats.processed_codewords += 1;
+        let x = x & 0xffffff;
+        let erasures = erasures & 0xffffff;
+
+        let decoded = if erasures == 0 {
+            Self::correct(x).map(|data| (data, self.encode(data) ^ x))
+        } else {
+            // Either all-zeroes or all-ones guess gets at least half of the erased bits right,
+            // which leaves at most 3 errors for the regular decoder when 2 * errors + erasures < 8
+            let known = !erasures & 0xffffff;
+            let mut best: Option<(u32, u32)> = None;
+            for fill in [0, erasures] {
+                if let Some(data) = Self::correct((x & known) | fill) {
+                    let pattern = (self.encode(data) ^ x) & known;
+                    if best.is_none_or(|(_, p)| pattern.count_ones() < p.count_ones()) {
+                        best = Some((data, pattern));
+                    }
+                }
+            }
+            best.filter(|(_, p)| 2 * p.count_ones() + erasures.count_ones() < 8)
+        };
+
+        match decoded {
+            Some((data, pattern)) => {
+                if pattern != 0 {
+                    self.stats.corrected_codewords += 1;
+                    for j in 0..24 {
+                        self.stats.corrected_bits[j] += ((pattern >> j) & 1) as u64;
+                    }
+                }
+                (data as i32, pattern)
+            }
+            None => {
+                self.stats.uncorrectable_codewords += 1;
+                (-1, 0)
+            }
+        }
+    }
 
+    /// Corrects up to 3 bit errors in a 24 bit codeword, returns 12 bits of data
+    fn correct(x: u32) -> Option<u32> {
         let received_data = (x >> 12) & 0xfff;
         let received_checksum = x & 0xfff;
         let expected_checksum = Self::checksum_bits(received_data);
 
         let syndrome = expected_checksum ^ received_checksum;
-        let weight = syndrome.count_ones() as i32;
+        let weight = syndrome.count_ones();
 
         if weight <= 3 {
-            if weight != 0 {
-                self.corrected_codewords += 1;
-            }
-            return received_data as i32;
+            return Some(received_data);
         }
 
-        for i in 0..12 {
+        for (i, &coding_error) in GOLAY_MATRIX.iter().enumerate() {
             let error_mask = 1u32 << (11 - i);
-            let coding_error = GOLAY_MATRIX[i];
             if (syndrome ^ coding_error).count_ones() <= 2 {
-                self.corrected_codewords += 1;
-                return (received_data ^ error_mask) as i32;
+                return Some(received_data ^ error_mask);
             }
         }
 
         let inverted_syndrome = Self::checksum_bits(syndrome);
         let w = inverted_syndrome.count_ones();
         if w <= 3 {
-            self.corrected_codewords += 1;
-            return (received_data ^ inverted_syndrome) as i32;
+            return Some(received_data ^ inverted_syndrome);
         }
 
-        for i in 0..12 {
-            let coding_error = GOLAY_MATRIX[i];
+        for coding_error in GOLAY_MATRIX {
             if (inverted_syndrome ^ coding_error).count_ones() <= 2 {
-                self.corrected_codewords += 1;
-                return (received_data ^ inverted_syndrome ^ coding_error) as i32;
+                return Some(received_data ^ inverted_syndrome ^ coding_error);
             }
         }
 
-        self.uncorrectable_codewords += 1;
-        -1
+        None
     }
 }
 
@@ -108,7 +190,7 @@ pub fn self_test() -> bool {
                     k += 1;
                 }
             }
-            let z = gc.decode(y ^ errors);
+            let (z, pattern) = gc.decode_with_error_pattern(y ^ errors, 0);
             if z < 0 {
                 not_decoded_ct[j] += 1;
             } else if z as u32 == x {
@@ -116,7 +198,7 @@ pub fn self_test() -> bool {
             } else {
                 decoded_wrong_ct[j] += 1;
             }
-            if z as u32 != x && j < 4 {
+            if (z as u32 != x || pattern != errors) && j < 4 {
                 eprintln!("GolayCode self-test failed");
                 eprintln!(
                     "Original:    0x{:03x}\nTransmitted: 0x{:06x}\nError bits:  0x{:06x}\nReceived:    0x{:06x}",
@@ -132,6 +214,53 @@ pub fn self_test() -> bool {
         }
     }
 
+    // Lost slices: one or two groups of 3 erased bits filled with garbage, plus as many
+    // random errors elsewhere as the remaining distance allows
+    for _i in 0..10000 {
+        let x = next_rand() & 0xfff;
+        let y = gc.encode(x);
+        let a = next_rand() % 8;
+        let b = next_rand() % 8;
+        let erasures = (7u32 << (a * 3)) | (7u32 << (b * 3));
+        let max_errors = (7 - erasures.count_ones()) / 2;
+        let mut errors = 0u32;
+        while errors.count_ones() < max_errors {
+            errors |= (1u32 << (next_rand() % 24)) & !erasures;
+        }
+        let garbage = (next_rand() | (next_rand() << 15)) & erasures;
+        let received = ((y ^ errors) & !erasures) | garbage;
+        let (z, pattern) = gc.decode_with_error_pattern(received, erasures);
+        if z < 0 || z as u32 != x || pattern != errors {
+            eprintln!("GolayCode erasure self-test failed");
+            eprintln!(
+                "Original:    0x{:03x}\nTransmitted: 0x{:06x}\nErasures:    0x{:06x}\nReceived:    0x{:06x}",
+                x, y, erasures, received
+            );
+            if z < 0 {
+                eprintln!("Nothing decoded");
+            } else {
+                eprintln!("Decoded:     0x{:03x}", z);
+            }
+            return false;
+        }
+    }
+
+    // Statistics of two halves merged must match statistics of the whole
+    let mut whole = GolayCode::new();
+    let mut halves = [GolayCode::new(), GolayCode::new()];
+    for i in 0..2000 {
+        let received = gc.encode(next_rand() & 0xfff) ^ (next_rand() | (next_rand() << 15));
+        whole.decode(received);
+        halves[i % 2].decode(received);
+    }
+    let mut merged = halves[0].stats.clone();
+    merged.merge(&halves[1].stats);
+    if merged != whole.stats {
+        eprintln!("GolayStats self-test failed");
+        eprintln!("Merged: {}\nWhole:  {}", merged, whole.stats);
+        return false;
+    }
+
     // Suppress unused warnings
     let _ = (not_decoded_ct, decoded_ok_ct, decoded_wrong_ct);
 
@@ -0,0 +1,210 @@
+//! Password-based key derivation built from Speck.
+//!
+//! Password, salt, and cost parameters are compressed into a 256-bit seed by two Davies-Meyer
+//! chains over Speck128/256, so every byte of a long passphrase counts. The seed keys an
+//! scrypt-style ROMix: a table of 2^log2_blocks 64-byte blocks is filled sequentially, then
+//! read back at data-dependent positions, so each password guess costs the whole table in
+//! memory as well as time.
+//!
+//! The same chains hash key files of any length into 32 bytes, which then stand in for the
+//! password.
+
+use std::io;
+
+use crate::error::PhnxError;
+use crate::key::Key;
+use crate::random;
+use crate::speck;
+
+/// Identifiers in a key slot: key used as is, or derived with Speck ROMix
+pub const KDF_NONE: u8 = 0;
+pub const KDF_SPECK_ROMIX: u8 = 1;
+
+/// 2^20 blocks of 64 bytes, 64 MiB
+pub const DEFAULT_LOG2_BLOCKS: u8 = 20;
+pub const DEFAULT_PASSES: u8 = 1;
+
+/// Initial values of the two Davies-Meyer chains
+const IV: [[u64; 2]; 2] = [[0, 0], [1, 0]];
+
+/// Follows key material before padding, where derive() has its identifier
+const KEY_MATERIAL: u8 = 0xff;
+
+/// Per-file salt and cost of key derivation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub salt: [u8; 16],
+    /// Table holds 2^log2_blocks blocks of 64 bytes
+    pub log2_blocks: u8,
+    /// Data-dependent passes over the table
+    pub passes: u8,
+}
+
+impl KdfParams {
+    /// Default cost with a fresh random salt, fails if the system has no random source
+    pub fn new() -> Result<Self, PhnxError> {
+        let mut salt = [0u8; 16];
+        random::fill(&mut salt)?;
+        Ok(KdfParams {
+            salt,
+            log2_blocks: DEFAULT_LOG2_BLOCKS,
+            passes: DEFAULT_PASSES,
+        })
+    }
+
+    /// Fails if the cost is beyond the default, the most a key is ever derived with. Anyone
+    /// who can add a slot could otherwise make it cost every opener more memory and time than
+    /// they ever agreed to, before their own slot is reached.
+    pub fn check_cost(&self) -> Result<(), PhnxError> {
+        if self.log2_blocks > DEFAULT_LOG2_BLOCKS || !(1..=DEFAULT_PASSES).contains(&self.passes) {
+            return Err(PhnxError::Format(format!(
+                "Key derivation cost out of range: 2^{} blocks, {} passes",
+                self.log2_blocks, self.passes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Derives the key, fails if the cost is beyond the default, see check_cost(), or the
+    /// table does not fit in memory
+    pub fn derive(&self, password: &[u8]) -> Result<Key, PhnxError> {
+        self.check_cost()?;
+        let seed = absorb(password, self);
+        let schedule = speck::speck_schedule(&seed);
+
+        let n = 1usize << self.log2_blocks;
+        let mut table: Vec<[u64; 8]> = Vec::new();
+        table.try_reserve_exact(n).map_err(|_| {
+            PhnxError::io(
+                format!("Cannot allocate {} MiB for key derivation", (n * 64) >> 20),
+                io::Error::from(io::ErrorKind::OutOfMemory),
+            )
+        })?;
+
+        let mut x = speck::speck_encrypt4(
+            &[seed[0], seed[1], seed[2], seed[3], 0, 1, 2, 3],
+            &schedule,
+        );
+        for _ in 0..n {
+            table.push(x);
+            x = mix(&x, &schedule);
+        }
+        for _ in 0..self.passes as usize * n {
+            let v = &table[x[0] as usize & (n - 1)];
+            for k in 0..8 {
+                x[k] ^= v[k];
+            }
+            x = mix(&x, &schedule);
+        }
+
+        let mut key = [0u8; 32];
+        for l in 0..4 {
+            key[l * 8..l * 8 + 8].copy_from_slice(&(x[l] ^ x[l + 4] ^ seed[l]).to_le_bytes());
+        }
+        Ok(Key::new(&key))
+    }
+}
+
+/// Hashes key material of any length, such as the contents of a key file, into 32 bytes that
+/// can stand in for a password
+pub fn hash_key_material(material: &[u8]) -> [u8; 32] {
+    let mut message = Vec::with_capacity(material.len() + 64);
+    message.extend_from_slice(material);
+    message.push(KEY_MATERIAL);
+    let h = compress(message, material.len());
+    let mut hash = [0u8; 32];
+    for i in 0..4 {
+        hash[i * 8..i * 8 + 8].copy_from_slice(&h[i].to_le_bytes());
+    }
+    hash
+}
+
+/// Compresses password, salt, and parameters into 256 bits
+fn absorb(password: &[u8], params: &KdfParams) -> [u64; 4] {
+    let mut message = Vec::with_capacity(password.len() + 64);
+    message.extend_from_slice(password);
+    message.extend_from_slice(&params.salt);
+    message.extend_from_slice(&[KDF_SPECK_ROMIX, params.log2_blocks, params.passes]);
+    compress(message, password.len())
+}
+
+/// Pads message and runs it through two Davies-Meyer chains, each 32-byte block serving as a
+/// Speck key
+fn compress(mut message: Vec<u8>, input_length: usize) -> [u64; 4] {
+    // Input length goes last, so that different inputs never pad to the same message
+    message.resize((message.len() + 8).next_multiple_of(32) - 8, 0);
+    message.extend_from_slice(&(input_length as u64).to_le_bytes());
+
+    let mut chains = IV;
+    for block in message.chunks_exact(32) {
+        let mut k = [0u64; 4];
+        for i in 0..4 {
+            k[i] = speck::bytes_to_uint64(&block[i * 8..i * 8 + 8]);
+        }
+        let schedule = speck::speck_schedule(&k);
+        for h in chains.iter_mut() {
+            let e = speck::speck_encrypt(h, &schedule);
+            h[0] ^= e[0];
+            h[1] ^= e[1];
+        }
+    }
+    [chains[0][0], chains[0][1], chains[1][0], chains[1][1]]
+}
+
+/// Folds each 128-bit lane (x[l], x[l + 4]) into its neighbour, then encrypts all four
+#[inline]
+fn mix(x: &[u64; 8], schedule: &[u64; 34]) -> [u64; 8] {
+    let mut y = *x;
+    for l in 0..4 {
+        y[l] ^= x[(l + 1) % 4 + 4];
+    }
+    speck::speck_encrypt4(&y, schedule)
+}
+
+pub fn self_test() -> bool {
+    let params = KdfParams {
+        salt: *b"phnx kdf selftst",
+        log2_blocks: 4,
+        passes: 1,
+    };
+    let derive = |password: &[u8], params: &KdfParams| {
+        let key = params.derive(password).expect("small table");
+        speck::speck_encrypt(&[0, 0], key.schedule())
+    };
+
+    let expected: [u64; 2] = [0xb34b2400aef31342, 0xdcf3b7b884845c1e];
+    let observed = derive(b"fourwordsalluppercase", &params);
+    if observed != expected {
+        eprintln!("KdfParams::derive() self-test failed");
+        eprintln!("Expected 0x{:x}, 0x{:x}", expected[0], expected[1]);
+        eprintln!("Observed 0x{:x}, 0x{:x}", observed[0], observed[1]);
+        return false;
+    }
+
+    // Bytes past 32 and a different salt must both change the key
+    let long = b"a passphrase well over thirty-two bytes long";
+    let mut longer = *long;
+    longer[40] ^= 1;
+    let other_salt = KdfParams {
+        salt: *b"phnx kdf selftsT",
+        ..params
+    };
+    if derive(long, &params) == derive(&longer, &params)
+        || derive(long, &params) == derive(long, &other_salt)
+    {
+        eprintln!("KdfParams::derive() self-test failed, key ignores part of its input");
+        return false;
+    }
+
+    // Every byte of key material counts too
+    let expected_hash: [u8; 8] = [0xe3, 0x65, 0x07, 0x29, 0xee, 0x8a, 0xe5, 0x0e];
+    let observed_hash = hash_key_material(long);
+    if observed_hash[..8] != expected_hash || observed_hash == hash_key_material(&longer) {
+        eprintln!("hash_key_material() self-test failed");
+        eprintln!("Expected {:02x?}", expected_hash);
+        eprintln!("Observed {:02x?}", &observed_hash[..8]);
+        return false;
+    }
+
+    true
+}
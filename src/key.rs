@@ -0,0 +1,47 @@
+use crate::speck;
+
+/// 256-bit Speck key, expanded into the round schedule once
+#[derive(Clone)]
+pub struct Key {
+    schedule: [u64; 34],
+}
+
+impl Key {
+    /// Takes 32 bytes of key material as four little-endian 64-bit words
+    pub fn new(key: &[u8; 32]) -> Self {
+        let mut k = [0u64; 4];
+        for i in 0..4 {
+            k[i] = speck::bytes_to_uint64(&key[i * 8..i * 8 + 8]);
+        }
+        Key {
+            schedule: speck::speck_schedule(&k),
+        }
+    }
+
+    /// Uses the first 32 bytes of the password directly as the key, zero padded
+    pub fn from_password(password: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        let len = std::cmp::min(password.len(), 32);
+        key[..len].copy_from_slice(&password[..len]);
+        Key::new(&key)
+    }
+
+    /// Subkey for one file, the two blocks of a 128-bit nonce encrypted with this key. Files
+    /// then share a keystream only if both this and the 64-bit nonce in every keystream
+    /// block repeat.
+    pub(crate) fn file_key(&self, nonce: &[u8; 16]) -> Key {
+        let n = [
+            speck::bytes_to_uint64(&nonce[0..8]),
+            speck::bytes_to_uint64(&nonce[8..16]),
+        ];
+        let lo = speck::speck_encrypt(&n, &self.schedule);
+        let hi = speck::speck_encrypt(&[!n[0], n[1]], &self.schedule);
+        Key {
+            schedule: speck::speck_schedule(&[lo[0], lo[1], hi[0], hi[1]]),
+        }
+    }
+
+    pub(crate) fn schedule(&self) -> &[u64; 34] {
+        &self.schedule
+    }
+}
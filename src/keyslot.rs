@@ -0,0 +1,188 @@
+//! Key slots, which let several passwords or keys open the same file.
+//!
+//! After version 1 data is encrypted under a random data key, and every slot holds a copy of
+//! it wrapped under a key of its own: derived from a password with the salt and cost stored
+//! in the slot, agreed with X25519 between a recipient's key pair and one made for the slot,
+//! or given as is. Slots sit in the trailer, so that passwords can be added or removed by
+//! rewriting the trailer alone.
+
+use crate::error::PhnxError;
+use crate::kdf::{self, KdfParams, KDF_NONE, KDF_SPECK_ROMIX};
+use crate::key::Key;
+use crate::poly1305::{tags_equal, Poly1305};
+use crate::random;
+use crate::speck;
+use crate::x25519;
+
+/// Bytes of a slot in the trailer, four 24 byte blocks
+pub(crate) const KEY_SLOT_SIZE: usize = 96;
+
+/// Byte 0 of a slot whose wrapping key is agreed with X25519
+const KEY_AGREEMENT_X25519: u8 = 2;
+
+/// How the key that wraps the data key in a slot is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Wrapping {
+    /// Given as is
+    Key,
+    /// Derived from a password with these parameters
+    Password(KdfParams),
+    /// Agreed between the private key of a recipient and an ephemeral key pair made for the
+    /// slot, of which only the public key is kept
+    Recipient { ephemeral: [u8; 32] },
+}
+
+/// One wrapped copy of the data key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeySlot {
+    pub wrapping: Wrapping,
+    nonce: u64,
+    wrapped: [u8; 32],
+    tag: [u8; 16],
+}
+
+impl KeySlot {
+    /// Wraps data_key under wrapping_key at a random nonce. wrapping is stored for finding
+    /// wrapping_key again.
+    pub fn wrap(
+        data_key: &[u8; 32],
+        wrapping_key: &Key,
+        wrapping: Wrapping,
+    ) -> Result<Self, PhnxError> {
+        let nonce = random::random_u64()?;
+        let keystream = keystream(wrapping_key, nonce);
+        let mut slot = KeySlot {
+            wrapping,
+            nonce,
+            wrapped: *data_key,
+            tag: [0; 16],
+        };
+        for (w, k) in slot.wrapped.iter_mut().zip(&keystream[32..]) {
+            *w ^= k;
+        }
+        slot.tag = tag(&keystream, &slot.to_bytes());
+        Ok(slot)
+    }
+
+    /// Data key, if wrapping_key is the one this slot was made with
+    pub fn unwrap(&self, wrapping_key: &Key) -> Option<[u8; 32]> {
+        let keystream = keystream(wrapping_key, self.nonce);
+        if !tags_equal(&tag(&keystream, &self.to_bytes()), &self.tag) {
+            return None;
+        }
+        let mut data_key = self.wrapped;
+        for (d, k) in data_key.iter_mut().zip(&keystream[32..]) {
+            *d ^= k;
+        }
+        Some(data_key)
+    }
+
+    /// KDF identifier, log2 of table blocks, passes, 5 zero bytes, salt, nonce, wrapped key,
+    /// tag of the 64 bytes before it, 16 zero bytes. Without a KDF its fields are zero. With
+    /// X25519 the ephemeral public key takes the place of the salt and the last 16 bytes.
+    pub fn to_bytes(self) -> [u8; KEY_SLOT_SIZE] {
+        let mut bytes = [0u8; KEY_SLOT_SIZE];
+        match &self.wrapping {
+            Wrapping::Key => bytes[0] = KDF_NONE,
+            Wrapping::Password(kdf) => {
+                bytes[0] = KDF_SPECK_ROMIX;
+                bytes[1] = kdf.log2_blocks;
+                bytes[2] = kdf.passes;
+                bytes[8..24].copy_from_slice(&kdf.salt);
+            }
+            Wrapping::Recipient { ephemeral } => {
+                bytes[0] = KEY_AGREEMENT_X25519;
+                bytes[8..24].copy_from_slice(&ephemeral[..16]);
+                bytes[80..96].copy_from_slice(&ephemeral[16..]);
+            }
+        }
+        bytes[24..32].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes[32..64].copy_from_slice(&self.wrapped);
+        bytes[64..80].copy_from_slice(&self.tag);
+        bytes
+    }
+
+    /// Fails on an unknown kind of slot, or a password slot costlier than this build writes
+    pub fn from_bytes(bytes: &[u8; KEY_SLOT_SIZE]) -> Result<Self, PhnxError> {
+        let wrapping = match bytes[0] {
+            KDF_SPECK_ROMIX => {
+                let params = KdfParams {
+                    salt: bytes[8..24].try_into().unwrap(),
+                    log2_blocks: bytes[1],
+                    passes: bytes[2],
+                };
+                params.check_cost()?;
+                Wrapping::Password(params)
+            }
+            KDF_NONE => Wrapping::Key,
+            KEY_AGREEMENT_X25519 => {
+                let mut ephemeral = [0u8; 32];
+                ephemeral[..16].copy_from_slice(&bytes[8..24]);
+                ephemeral[16..].copy_from_slice(&bytes[80..96]);
+                Wrapping::Recipient { ephemeral }
+            }
+            kdf => {
+                return Err(PhnxError::Format(format!(
+                    "Unknown key derivation function {}",
+                    kdf
+                )))
+            }
+        };
+        Ok(KeySlot {
+            wrapping,
+            nonce: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            wrapped: bytes[32..64].try_into().unwrap(),
+            tag: bytes[64..80].try_into().unwrap(),
+        })
+    }
+}
+
+/// Makes an ephemeral key pair for a slot to the holder of the private key of recipient,
+/// returns the wrapping key and the ephemeral public key. Fails if recipient is not a usable
+/// public key.
+pub(crate) fn recipient_key(recipient: &[u8; 32]) -> Result<(Key, [u8; 32]), PhnxError> {
+    let mut ephemeral_private = [0u8; 32];
+    random::fill(&mut ephemeral_private)?;
+    let ephemeral = x25519::public_key(&ephemeral_private);
+    let key = agreed_key(&ephemeral_private, recipient, &ephemeral, recipient).ok_or_else(|| {
+        PhnxError::Format("Recipient is not a usable X25519 public key".to_string())
+    })?;
+    Ok((key, ephemeral))
+}
+
+/// Wrapping key of a slot with the given ephemeral public key, found by the recipient
+pub(crate) fn recipient_unwrap_key(private_key: &[u8; 32], ephemeral: &[u8; 32]) -> Option<Key> {
+    let public = x25519::public_key(private_key);
+    agreed_key(private_key, ephemeral, ephemeral, &public)
+}
+
+/// Hash of the shared secret of private_key and public_key, with both public keys of the
+/// slot. None if the secret is zero, which points of small order give.
+fn agreed_key(
+    private_key: &[u8; 32],
+    public_key: &[u8; 32],
+    ephemeral: &[u8; 32],
+    recipient: &[u8; 32],
+) -> Option<Key> {
+    let shared = x25519::x25519(private_key, public_key);
+    if shared == [0; 32] {
+        return None;
+    }
+    let material = [&shared[..], ephemeral, recipient].concat();
+    Some(Key::new(&kdf::hash_key_material(&material)))
+}
+
+/// Four keystream blocks at the nonce: the Poly1305 key, then what the data key is XORed with
+fn keystream(wrapping_key: &Key, nonce: u64) -> [u8; 64] {
+    let mut keystream = [0u8; 64];
+    let mut counter = [nonce, nonce, nonce, nonce, 0, 1, 2, 3];
+    speck::speck_ctr(&mut keystream, &mut counter, wrapping_key.schedule());
+    keystream
+}
+
+/// Tag of everything in the slot before the tag
+fn tag(keystream: &[u8; 64], bytes: &[u8; KEY_SLOT_SIZE]) -> [u8; 16] {
+    let mut mac = Poly1305::new(keystream[..32].try_into().unwrap());
+    mac.update(&bytes[..64]);
+    mac.finalize()
+}
@@ -0,0 +1,77 @@
+//! phnx - encryption and error correction.
+//!
+//! Encrypts a file with Speck128/256 in CTR mode, adds Extended Binary Golay Code (24,12,8)
+//! or Reed-Solomon error correction bits, and splits the result into slices, eight by default.
+//! The original can be recovered from any six of eight, or as many as the codec allows. The
+//! key is derived from a password by a memory-hard function built from Speck.
+//!
+//! ```
+//! use std::io::Cursor;
+//! use phnx::{Decoder, Encoder, Key};
+//!
+//! let key = Key::new(&[7; 32]);
+//! let data = b"Any six of eight slices will do".to_vec();
+//! let mut slices = vec![Vec::new(); 8];
+//! Encoder::new(&key).encode(Cursor::new(&data), slices.iter_mut())?;
+//!
+//! // Slice C is lost, the others still decode
+//! let mut slices: Vec<_> = slices.into_iter().map(|s| Some(Cursor::new(s))).collect();
+//! slices[2] = None;
+//! let mut decoded = Vec::new();
+//! Decoder::new(&key, slices)?.decode(&mut decoded)?;
+//! assert_eq!(decoded, data);
+//! # Ok::<(), phnx::PhnxError>(())
+//! ```
+
+mod codec;
+pub mod cpu;
+pub mod crc32c;
+mod error;
+pub mod golay;
+pub mod kdf;
+mod key;
+mod keyslot;
+mod metadata;
+mod pipeline;
+pub mod poly1305;
+mod process;
+mod random;
+pub mod reed_solomon;
+mod slices;
+pub mod speck;
+mod tree;
+pub mod x25519;
+
+pub use codec::{
+    max_missing_slices, Codec, CodecParams, GolayCodec, CODEC_GOLAY_24_12, CODEC_REED_SOLOMON,
+    DEFAULT_SLICE_COUNT, MAX_SLICE_COUNT, SLICE_COUNTS,
+};
+pub use error::{
+    PhnxError, PHNX_AUTH_FAILED, PHNX_FORMAT_ERROR, PHNX_IO_ERROR, PHNX_OK,
+    PHNX_SELF_TEST_FAILED, PHNX_UNCORRECTABLE_ERROR, PHNX_WRONG_PASSWORD,
+};
+pub use kdf::KdfParams;
+pub use key::Key;
+pub use metadata::FileMetadata;
+pub use process::{
+    decode_stream, encode_stream, process_one_file, rekey_one_file, repair_one_file,
+    slice_base_name, slice_filename, verify_one_file, Options,
+};
+pub use slices::{
+    arrange_slices, regenerate_slices, slice_letter, verify_slices, DamageFill, Decoder, Encoder,
+    ErrorCluster, Report, SliceErrors, SliceHeader, SliceHealth, SliceNote, SliceSet, Trailer,
+    DAMAGE_MARKER, SLICE_HEADER_SIZE,
+};
+pub use tree::{process_tree, EntryKind, ManifestEntry, TreeReport, MANIFEST_NAME};
+
+/// Runs built-in tests of the cipher, MAC, key derivation, key agreement, checksum, and the
+/// error correcting codes
+pub fn self_test() -> bool {
+    speck::self_test()
+        && poly1305::self_test()
+        && kdf::self_test()
+        && x25519::self_test()
+        && crc32c::self_test()
+        && golay::self_test()
+        && reed_solomon::self_test()
+}
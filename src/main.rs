@@ -1,141 +1,756 @@
-mod crc32c;
-mod golay;
-mod process;
-mod speck;
-
 use std::env;
-use std::io::{self, BufRead, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
 
 const PHNX_VERSION: &str = "4.0.1";
-const PHNX_SELF_TEST_FAILED: i32 = 5;
+
+/// Start of the text form of X25519 keys, followed by 64 hex digits
+const PUBLIC_KEY_PREFIX: &str = "phnx-public-";
+const PRIVATE_KEY_PREFIX: &str = "phnx-private-";
+
+/// Counts processed files and remembers the last error
+struct Tally {
+    ok_ct: u32,
+    fail_ct: u32,
+    last_error_code: i32,
+}
+
+impl Tally {
+    fn new() -> Self {
+        Tally {
+            ok_ct: 0,
+            fail_ct: 0,
+            last_error_code: phnx::PHNX_OK,
+        }
+    }
+
+    fn record<T>(&mut self, result: Result<T, phnx::PhnxError>) {
+        match result {
+            Ok(_) => self.ok_ct += 1,
+            Err(e) => {
+                eprintln!("{}", e);
+                self.last_error_code = e.code();
+                self.fail_ct += 1;
+            }
+        }
+    }
+
+    /// Counts every file of a tree, and prints those that failed
+    fn record_tree(&mut self, result: Result<phnx::TreeReport, phnx::PhnxError>) {
+        match result {
+            Ok(report) => {
+                self.ok_ct += report.files as u32;
+                for (path, e) in report.failed {
+                    eprintln!("Failed {}: {}", path, e);
+                    self.last_error_code = e.code();
+                    self.fail_ct += 1;
+                }
+            }
+            Err(e) => self.record::<()>(Err(e)),
+        }
+    }
+
+    fn exit(self) -> ! {
+        if self.ok_ct + self.fail_ct > 1 {
+            eprintln!("{} files, {} errors", self.ok_ct + self.fail_ct, self.fail_ct);
+        }
+        std::process::exit(self.last_error_code);
+    }
+}
+
+/// Where the password comes from instead of PHNX_PASSWORD or a prompt. When encoding, every
+/// source after the first adds another password or key the slices can be decoded with.
+enum PasswordSource {
+    /// File of key material, hashed into a key that is used as is, without key derivation
+    Keyfile(String),
+    /// Descriptor to read the password from, such as a pipe from the parent process
+    Fd(u32),
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() <= 1 {
         // Run self-tests
-        if !speck::self_test() {
-            std::process::exit(PHNX_SELF_TEST_FAILED);
-        }
-
-        if !golay::self_test() {
-            std::process::exit(PHNX_SELF_TEST_FAILED);
+        if !phnx::self_test() {
+            std::process::exit(phnx::PHNX_SELF_TEST_FAILED);
         }
 
         eprintln!(
             "phnx version {} (Rust)\n\n\
-             Usage:\n\n\t{} [-c] file1 [-g] [file2] [...]\n\n\
+             Usage:\n\n\t{1} [-c] [--keep-damaged | --mark-damaged] [--no-metadata] [--hide-name] [--threads n] [--slices n | --codec c] [--outdir dir] [--force] [--keyfile path | --password-fd n | --recipient key]... [--private-key path] file1 [-g] [file2] [...]\n\t{1} [options] --output path file\n\t{1} -r [--hidden] [options] dir1 [dir2] [...]\n\t{1} encode [--threads n] [--slices n | --codec c] [--force] [--keyfile path | --password-fd n | --recipient key]... --out name < input\n\t{1} decode [--keep-damaged | --mark-damaged] [--threads n] [--private-key path] [--range start-end] slice > output\n\t{1} rekey [--keyfile path | --password-fd n | --private-key path] [--add] [--new-keyfile path | --new-password-fd n | --recipient key]... slice1 [slice2] [...]\n\t{1} keygen name\n\t{1} repair slice1 [slice2] [...]\n\t{1} verify slice1 [slice2] [...]\n\n\
              Encrypt a given file or files, add error correction bits, split into eight slices.\n\
-             When given a slice, read all eight slices, correct errors if possible, then decrypt the original file.\n\
+             When given a slice, read all slices of its set, correct errors if possible, then decrypt the original file.\n\
              Option -c turns on compatibility mode (encryption only, no error correction) for the files that follow,\n\
-             option -g turns it off. Password can be passed via environment variable PHNX_PASSWORD.",
+             option -g turns it off. Password can be passed via environment variable PHNX_PASSWORD.\n\
+             A decoded file with parts that could not be recovered is removed, unless option --keep-damaged\n\
+             (fill those parts with zeroes) or --mark-damaged (fill them with \"DAMAGED!\") comes before it.\n\
+             Command encode reads standard input until it ends and writes slices of the given name,\n\
+             command decode writes the file a set of slices holds to standard output. Both work with pipes.\n\
+             Option --range makes decode write only bytes start up to end of the file, reading only the\n\
+             chunks they are in. Either may be a sum like 10G+4M, with K, M, G, or T for powers of 1024;\n\
+             without end the range goes to the end of the file.\n\
+             Option --threads sets how many threads encode and decode slices, by default one per core.\n\
+             Option --slices splits new sets into 4, 6, 8, 12, or 24 slices instead of eight, of which\n\
+             1, 1, 2, 3, or 7 may be lost. Option --codec rs:K+M uses Reed-Solomon instead of Golay code,\n\
+             with K slices of data and M of parity, any M of which may be lost; --codec golay goes back.\n\
+             Decoding finds the codec and the number of slices in the slices.\n\
+             Command repair regenerates missing or damaged slices of a set without decrypting it,\n\
+             command verify only reports errors found in each slice. Neither needs a password.\n\
+             Option -r makes the directories that follow it encode every file in them and write an\n\
+             encrypted manifest of the tree, or restore the tree if the manifest is there. Names starting\n\
+             with a dot are skipped unless --hidden comes before them, links are recorded, not followed.\n\
+             Permissions, owner, times, and extended attributes of a file are stored with it and restored\n\
+             when decoding, unless option --no-metadata comes before it.\n\
+             Option --hide-name names the slices of the files that follow with a random id, and keeps\n\
+             the file name encrypted in them; decoding gives the name back. Option --output names the\n\
+             slices of the file that follows it, or the file the slice that follows decodes into.\n\
+             Option --outdir writes slices and decoded files into the given directory instead of next\n\
+             to what they come from. Existing files are not replaced unless option --force is given;\n\
+             outputs are written to temporary files and only renamed into place once complete and checked.\n\
+             Option --keyfile takes the key from the contents of a file instead of a password, option\n\
+             --password-fd reads the password from the given file descriptor. Anywhere on the command\n\
+             line, either applies to all files. Given more than once, new slices can be decoded with\n\
+             any of them, and decoding uses the first. The password is typed without echo, and asked\n\
+             for twice only if something is encrypted.\n\
+             Command keygen writes a new X25519 private key to name and its public key to name.pub.\n\
+             Option --recipient encrypts for the holder of the private key of the given public key, or\n\
+             of the one in the given file; given only recipients, no password is asked for. Option\n\
+             --private-key decodes with the private key in the given file instead of a password.\n\
+             Command rekey changes the password of a set by rewriting only the end of every slice in\n\
+             place; if interrupted, running it again rolls the set back first. The new password is\n\
+             asked for, or taken from --new-keyfile or --new-password-fd; these and --recipient\n\
+             replace what opened the set before, or join it with option --add. The data key stays the\n\
+             same, so whoever kept it can still decrypt; to revoke access fully, decode and encode\n\
+             again.",
             PHNX_VERSION, args[0]
         );
 
-        #[cfg(all(target_feature = "sse4.2", target_feature = "avx2", target_feature = "bmi2"))]
-        eprintln!("Will use SSE4.2, AVX2, and BMI instructions.");
-
-        #[cfg(all(target_feature = "sse4.2", target_feature = "avx2", not(target_feature = "bmi2")))]
-        eprintln!("Will use SSE4.2 and AVX2 instructions.");
+        // Names what this CPU has, not what the binary was built for
+        let cpu = phnx::cpu::features();
+        let found: Vec<&str> = [(cpu.sse42, "SSE4.2"), (cpu.avx2, "AVX2"), (cpu.bmi2, "BMI2")]
+            .into_iter()
+            .filter_map(|(has, name)| has.then_some(name))
+            .collect();
+        match found[..] {
+            [] if env::var_os(phnx::cpu::PORTABLE_VAR).is_some() => {
+                eprintln!("{} is set, will use portable code.", phnx::cpu::PORTABLE_VAR)
+            }
+            [] => eprintln!("Found no SSE4.2, AVX2, or BMI2, will use portable code."),
+            [a] => eprintln!("Will use {} instructions.", a),
+            [a, b] => eprintln!("Will use {} and {} instructions.", a, b),
+            _ => eprintln!("Will use {}, and {} instructions.", found[..2].join(", "), found[2]),
+        }
 
-        #[cfg(all(target_feature = "bmi2", not(target_feature = "avx2")))]
-        eprintln!("Will use BMI2 instructions.");
+        std::process::exit(phnx::PHNX_OK);
+    }
 
-        std::process::exit(process::PHNX_OK);
+    if args[1] == "keygen" {
+        let [name] = &args[2..] else {
+            eprintln!("Usage: {} keygen name", args[0]);
+            std::process::exit(phnx::PHNX_IO_ERROR);
+        };
+        let mut tally = Tally::new();
+        tally.record(keygen(name));
+        tally.exit();
     }
 
-    let mut first_attempt = String::new();
-    let password: String;
+    if args[1] == "repair" {
+        let mut tally = Tally::new();
+        for filename in &args[2..] {
+            tally.record(phnx::repair_one_file(filename));
+        }
+        tally.exit();
+    }
 
-    match env::var("PHNX_PASSWORD") {
-        Ok(pw) => {
-            eprintln!("Using password from environment variable");
-            password = pw;
+    if args[1] == "verify" {
+        let mut tally = Tally::new();
+        for filename in &args[2..] {
+            tally.record(phnx::verify_one_file(filename));
         }
-        Err(_) => {
-            let stdin = io::stdin();
-            let mut reader = stdin.lock();
-
-            eprint!("Enter encryption key (32 chars max): ");
-            io::stderr().flush().ok();
-            reader.read_line(&mut first_attempt).ok();
-            // Strip trailing newline
-            if first_attempt.ends_with('\n') {
-                first_attempt.pop();
-                if first_attempt.ends_with('\r') {
-                    first_attempt.pop();
+        tally.exit();
+    }
+
+    if args[1] == "rekey" {
+        let mut options = phnx::Options::default();
+        let mut sources = Vec::new();
+        let mut new_sources = Vec::new();
+        let mut filenames = Vec::new();
+        let mut rest = args[2..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--keyfile" => sources.push(PasswordSource::Keyfile(parse_path(arg, rest.next()))),
+                "--password-fd" => sources.push(PasswordSource::Fd(parse_fd(rest.next()))),
+                "--private-key" => options.private_key = Some(read_private_key(rest.next())),
+                "--new-keyfile" => {
+                    new_sources.push(PasswordSource::Keyfile(parse_path(arg, rest.next())))
                 }
+                "--new-password-fd" => new_sources.push(PasswordSource::Fd(parse_fd(rest.next()))),
+                "--recipient" => options.recipients.push(parse_recipient(rest.next())),
+                "--add" => options.keep_slots = true,
+                _ => filenames.push(arg),
             }
+        }
+        if filenames.is_empty() {
+            eprintln!(
+                "Usage: {} rekey [--keyfile path | --password-fd n | --private-key path] [--add] \
+                 [--new-keyfile path | --new-password-fd n | --recipient key]... slice1 [...]",
+                args[0]
+            );
+            std::process::exit(phnx::PHNX_IO_ERROR);
+        }
 
-            eprint!("Enter encryption key again         : ");
-            io::stderr().flush().ok();
-            let mut second_attempt = String::new();
-            reader.read_line(&mut second_attempt).ok();
-            if second_attempt.ends_with('\n') {
-                second_attempt.pop();
-                if second_attempt.ends_with('\r') {
-                    second_attempt.pop();
+        let password = match options.private_key {
+            Some(_) => Vec::new(),
+            None => {
+                let (password, key) = read_secret(sources.first(), false, false);
+                options.key = key;
+                password
+            }
+        };
+        // PHNX_PASSWORD holds the current password, so a new one is asked for unless given
+        // otherwise. Given only recipients, there is nothing to ask.
+        let new_password = if new_sources.is_empty() && options.recipients.is_empty() {
+            warn_if_short(ask_password("new password", false, true))
+        } else {
+            Vec::new()
+        };
+        (options.extra_passwords, options.extra_keys) = read_extra_secrets(&new_sources);
+        let mut tally = Tally::new();
+        for filename in filenames {
+            tally.record(phnx::rekey_one_file(filename, &password, &new_password, &options));
+        }
+        tally.exit();
+    }
+
+    if args[1] == "encode" {
+        let mut options = phnx::Options::default();
+        let mut out = None;
+        let mut sources = Vec::new();
+        let mut rest = args[2..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--out" if out.is_none() => out = rest.next(),
+                "--threads" => options.threads = parse_threads(rest.next()),
+                "--slices" => options.codec = parse_slices(rest.next()),
+                "--codec" => options.codec = parse_codec(rest.next()),
+                "--force" => options.force = true,
+                "--keyfile" => sources.push(PasswordSource::Keyfile(parse_path(arg, rest.next()))),
+                "--password-fd" => sources.push(PasswordSource::Fd(parse_fd(rest.next()))),
+                "--recipient" => options.recipients.push(parse_recipient(rest.next())),
+                _ => {
+                    out = None;
+                    break;
                 }
             }
+        }
+        let Some(out) = out else {
+            eprintln!(
+                "Usage: {} encode [--threads n] [--slices n | --codec c] [--force] \
+                 [--keyfile path | --password-fd n | --recipient key]... --out name < input",
+                args[0]
+            );
+            std::process::exit(phnx::PHNX_IO_ERROR);
+        };
+
+        // Standard input carries the data to encode, so ask on the terminal instead. Given
+        // only recipients, there is nothing to ask.
+        let (password, key) = if sources.is_empty() && !options.recipients.is_empty() {
+            (Vec::new(), None)
+        } else {
+            read_secret(sources.first(), true, true)
+        };
+        options.key = key;
+        (options.extra_passwords, options.extra_keys) =
+            read_extra_secrets(sources.get(1..).unwrap_or_default());
+        let mut tally = Tally::new();
+        tally.record(phnx::encode_stream(io::stdin(), out, &password, &options));
+        tally.exit();
+    }
+
+    // Collect files, or slices to decode to standard output, each with the options before it.
+    // The password is read once it is known whether anything gets encrypted.
+    let to_stdout = args[1] == "decode";
+    let mut jobs = Vec::new();
+    let mut options = phnx::Options::default();
+    let mut recursive = false;
+    let mut sources = Vec::new();
+    let mut recipients = Vec::new();
+    let mut private_key = None;
+    let mut rest = args[if to_stdout { 2 } else { 1 }..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-c" => options.compatibility_mode = true,
+            "-g" => options.compatibility_mode = false,
+            "--keep-damaged" => options.keep_damaged = Some(phnx::DamageFill::Zeroes),
+            "--mark-damaged" => options.keep_damaged = Some(phnx::DamageFill::Marker),
+            "--threads" => options.threads = parse_threads(rest.next()),
+            "--slices" if !to_stdout => options.codec = parse_slices(rest.next()),
+            "--codec" if !to_stdout => options.codec = parse_codec(rest.next()),
+            "-r" if !to_stdout => recursive = true,
+            "--hidden" if !to_stdout => options.hidden = true,
+            "--no-metadata" if !to_stdout => options.skip_metadata = true,
+            "--hide-name" if !to_stdout => options.hide_name = true,
+            "--output" if !to_stdout => options.output = Some(parse_path(arg, rest.next())),
+            "--outdir" if !to_stdout => options.outdir = Some(parse_path(arg, rest.next())),
+            "--force" if !to_stdout => options.force = true,
+            "--keyfile" => sources.push(PasswordSource::Keyfile(parse_path(arg, rest.next()))),
+            "--password-fd" => sources.push(PasswordSource::Fd(parse_fd(rest.next()))),
+            "--recipient" if !to_stdout => recipients.push(parse_recipient(rest.next())),
+            "--private-key" => private_key = Some(read_private_key(rest.next())),
+            "--range" if to_stdout => options.range = Some(parse_range(rest.next())),
+            _ => {
+                jobs.push((arg, options.clone(), recursive));
+                // Names one file only, others would overwrite it
+                options.output = None;
+            }
+        }
+    }
+
+    let mut tally = Tally::new();
+    if jobs.is_empty() {
+        tally.exit();
+    }
+    let encrypting = !to_stdout && jobs.iter().any(|(arg, _, recursive)| encrypts(arg, *recursive));
+    let decoding = to_stdout || jobs.iter().any(|(arg, _, recursive)| !encrypts(arg, *recursive));
+    // Recipients stand in for the password when encrypting, a private key when decoding
+    let (password, key) = if sources.is_empty()
+        && !(encrypting && recipients.is_empty())
+        && !(decoding && private_key.is_none())
+    {
+        (Vec::new(), None)
+    } else {
+        read_secret(sources.first(), false, encrypting)
+    };
+    let (extra_passwords, extra_keys) = read_extra_secrets(sources.get(1..).unwrap_or_default());
+    for (arg, options, recursive) in &mut jobs {
+        options.key = key;
+        options.extra_passwords = extra_passwords.clone();
+        options.extra_keys = extra_keys.clone();
+        options.recipients = recipients.clone();
+        options.private_key = private_key;
+        if to_stdout {
+            let output = BufWriter::new(io::stdout().lock());
+            tally.record(phnx::decode_stream(arg, &password, options, output));
+        } else if *recursive {
+            tally.record_tree(phnx::process_tree(arg, &password, options));
+        } else {
+            tally.record(phnx::process_one_file(arg, &password, options));
+        }
+    }
+    tally.exit();
+}
+
+/// Whether processing path encrypts anything, in which case a mistyped password would go
+/// unnoticed unless asked for twice
+fn encrypts(path: &str, recursive: bool) -> bool {
+    if recursive {
+        let manifest = Path::new(path).join(phnx::MANIFEST_NAME);
+        let manifest = manifest.to_string_lossy();
+        return !(0..phnx::MAX_SLICE_COUNT)
+            .any(|i| Path::new(&phnx::slice_filename(&manifest, i)).exists());
+    }
+    let legacy = path.ends_with(".encrypted")
+        || path.rfind(".encrypted-").is_some_and(|pos| {
+            let hex = &path[pos + 11..];
+            !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+        });
+    phnx::slice_base_name(path).is_none() && !legacy
+}
 
-            if first_attempt != second_attempt {
-                eprintln!("Keys don't match");
-                std::process::exit(process::PHNX_WRONG_PASSWORD);
+/// Password from the descriptor given, else PHNX_PASSWORD, else asked for on standard
+/// input, or on the terminal if standard input carries data; or the key of the key file given
+/// instead, with an empty password. Asks twice if confirm is set.
+fn read_secret(
+    source: Option<&PasswordSource>,
+    stdin_is_data: bool,
+    confirm: bool,
+) -> (Vec<u8>, Option<[u8; 32]>) {
+    match source {
+        Some(PasswordSource::Keyfile(path)) => (Vec::new(), Some(read_key_file(path))),
+        Some(PasswordSource::Fd(fd)) => (read_password(Some(*fd), stdin_is_data, confirm), None),
+        None => (read_password(None, stdin_is_data, confirm), None),
+    }
+}
+
+/// Contents of a key file hashed into a key, so that it may be any length and every byte
+/// counts. Exits if it cannot be read or is empty.
+fn read_key_file(path: &str) -> [u8; 32] {
+    let material = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Cannot read key file {}: {}", path, e);
+        std::process::exit(phnx::PHNX_IO_ERROR);
+    });
+    if material.is_empty() {
+        eprintln!("Key file {} is empty", path);
+        std::process::exit(phnx::PHNX_IO_ERROR);
+    }
+    eprintln!("Using key file {}", path);
+    phnx::kdf::hash_key_material(&material)
+}
+
+/// Takes the password from the descriptor given, else PHNX_PASSWORD, else asks for it
+fn read_password(fd: Option<u32>, stdin_is_data: bool, confirm: bool) -> Vec<u8> {
+    let password = match fd {
+        Some(fd) => {
+            // Opened anew, so that a wrong number fails here instead of closing something else
+            let read = File::open(format!("/dev/fd/{}", fd)).and_then(|f| {
+                let mut line = String::new();
+                BufReader::new(f).read_line(&mut line)?;
+                Ok(line)
+            });
+            match read {
+                Ok(line) => {
+                    eprintln!("Using password from file descriptor {}", fd);
+                    trim_line_ending(line)
+                }
+                Err(e) => {
+                    eprintln!("Cannot read password from file descriptor {}: {}", fd, e);
+                    std::process::exit(phnx::PHNX_IO_ERROR);
+                }
             }
-            password = first_attempt;
+        }
+        None => match env::var("PHNX_PASSWORD") {
+            Ok(pw) => {
+                eprintln!("Using password from environment variable");
+                pw
+            }
+            Err(_) => ask_password("password", stdin_is_data, confirm),
+        },
+    };
+    warn_if_short(password)
+}
+
+/// Asks on standard input, or on the terminal if standard input carries data
+fn ask_password(label: &str, stdin_is_data: bool, confirm: bool) -> String {
+    if !stdin_is_data {
+        let stdin = io::stdin();
+        let _echo = echo::off(&stdin);
+        return prompt_password(&mut stdin.lock(), label, confirm);
+    }
+    match File::open("/dev/tty") {
+        Ok(tty) => {
+            let _echo = echo::off(&tty);
+            prompt_password(&mut BufReader::new(&tty), label, confirm)
+        }
+        Err(_) => {
+            eprintln!("No terminal to ask for the password on, set PHNX_PASSWORD");
+            std::process::exit(phnx::PHNX_IO_ERROR);
         }
     }
+}
 
-    // Convert password to four little-endian 64-bit words
-    let pw_bytes = password.as_bytes();
-    let mut bytes_left = pw_bytes.len();
-    if bytes_left < 16 {
+/// Slices derive their key from the whole password, legacy files use its first 32 bytes
+fn warn_if_short(password: String) -> Vec<u8> {
+    if password.len() < 16 {
         eprintln!("WARNING: password is less than 16 characters long");
-    } else if bytes_left > 32 {
-        eprintln!(
-            "WARNING: password is longer than 32 characters, only using the first 32"
-        );
     }
+    password.into_bytes()
+}
 
-    let mut k = [0u64; 4];
-    for i in 0..4 {
-        let start = i * 8;
-        let len = if bytes_left > 8 { 8 } else { bytes_left };
-        k[i] = speck::bytes_to_uint64(&pw_bytes[start..start + len]);
-        if bytes_left <= 8 {
-            break;
+/// Passwords from descriptors and keys from key files, for new slices to be decoded with too
+fn read_extra_secrets(sources: &[PasswordSource]) -> (Vec<Vec<u8>>, Vec<[u8; 32]>) {
+    let mut passwords = Vec::new();
+    let mut keys = Vec::new();
+    for source in sources {
+        match source {
+            PasswordSource::Keyfile(path) => keys.push(read_key_file(path)),
+            PasswordSource::Fd(fd) => passwords.push(read_password(Some(*fd), false, false)),
         }
-        bytes_left -= 8;
     }
+    (passwords, keys)
+}
+
+/// Writes a new X25519 private key to name, readable by the owner alone, and its public key
+/// to name.pub. Neither file may exist yet.
+fn keygen(name: &str) -> Result<(), phnx::PhnxError> {
+    let private_key = phnx::x25519::generate_private_key()?;
+    let public_key = format_key(PUBLIC_KEY_PREFIX, &phnx::x25519::public_key(&private_key));
+    let public_name = format!("{}.pub", name);
+
+    let mut private_open = OpenOptions::new();
+    private_open.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        private_open.mode(0o600);
+    }
+    let mut public_open = OpenOptions::new();
+    public_open.write(true).create_new(true);
+    for (open, path, text) in [
+        (private_open, name, format_key(PRIVATE_KEY_PREFIX, &private_key)),
+        (public_open, &public_name, public_key.clone()),
+    ] {
+        open.open(path)
+            .and_then(|mut f| writeln!(f, "{}", text))
+            .map_err(|e| phnx::PhnxError::io(format!("Cannot write {}", path), e))?;
+    }
+    println!("Wrote private key {} and public key {}", name, public_name);
+    println!("{}", public_key);
+    Ok(())
+}
 
-    let schedule = speck::speck_schedule(&k);
+/// Text form of a key: prefix and 64 hex digits
+fn format_key(prefix: &str, key: &[u8; 32]) -> String {
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", prefix, hex)
+}
 
-    // Iterate over files
-    let mut ok_ct: u32 = 0;
-    let mut fail_ct: u32 = 0;
-    let mut compatibility_mode = false;
-    let mut last_error_code = process::PHNX_OK;
+/// Key from its text form, None unless it has the prefix and 64 hex digits
+fn parse_key(prefix: &str, text: &str) -> Option<[u8; 32]> {
+    let hex = text.trim().strip_prefix(prefix)?;
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
 
-    for i in 1..args.len() {
-        if args[i] == "-c" {
-            compatibility_mode = true;
-            continue;
+/// Value of --recipient, a public key or a file holding one, exits if it is neither
+fn parse_recipient(value: Option<&String>) -> [u8; 32] {
+    let value = parse_path("--recipient", value);
+    let key = parse_key(PUBLIC_KEY_PREFIX, &value)
+        .or_else(|| parse_key(PUBLIC_KEY_PREFIX, &fs::read_to_string(&value).ok()?));
+    key.unwrap_or_else(|| {
+        eprintln!("Option --recipient needs a public key from keygen, or a file holding one");
+        std::process::exit(phnx::PHNX_IO_ERROR);
+    })
+}
+
+/// Private key in the file given to --private-key, exits if it cannot be read
+fn read_private_key(value: Option<&String>) -> [u8; 32] {
+    let path = parse_path("--private-key", value);
+    let text = fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("Cannot read private key {}: {}", path, e);
+        std::process::exit(phnx::PHNX_IO_ERROR);
+    });
+    parse_key(PRIVATE_KEY_PREFIX, &text).unwrap_or_else(|| {
+        eprintln!("{} is not a private key from keygen", path);
+        std::process::exit(phnx::PHNX_IO_ERROR);
+    })
+}
+
+/// Value of --threads, exits if it is missing or not a number
+fn parse_threads(value: Option<&String>) -> usize {
+    match value.and_then(|n| n.parse().ok()) {
+        Some(n) => n,
+        None => {
+            eprintln!("Option --threads needs a number of threads, 0 for one per core");
+            std::process::exit(phnx::PHNX_IO_ERROR);
         }
-        if args[i] == "-g" {
-            compatibility_mode = false;
-            continue;
+    }
+}
+
+/// Value of --range, START-END where either is a sum of sizes and END may be left out for the
+/// end of the file. Exits unless it parses and START is not past END.
+fn parse_range(value: Option<&String>) -> Range<u64> {
+    let range = value.and_then(|value| {
+        let (start, end) = value.split_once('-')?;
+        let start = parse_size(start)?;
+        let end = if end.is_empty() { u64::MAX } else { parse_size(end)? };
+        (start <= end).then_some(start..end)
+    });
+    match range {
+        Some(range) => range,
+        None => {
+            eprintln!("Option --range needs start-end, such as 10G-10G+4M or 1M-");
+            std::process::exit(phnx::PHNX_IO_ERROR);
         }
-        let result = process::process_one_file(&args[i], &schedule, compatibility_mode);
-        if result != process::PHNX_OK {
-            last_error_code = result;
-            fail_ct += 1;
-        } else {
-            ok_ct += 1;
+    }
+}
+
+/// Sum of numbers joined by +, each with an optional K, M, G, or T suffix
+fn parse_size(value: &str) -> Option<u64> {
+    value.split('+').try_fold(0u64, |sum, term| {
+        let (digits, shift) = [('K', 10), ('M', 20), ('G', 30), ('T', 40)]
+            .into_iter()
+            .find_map(|(suffix, shift)| Some((term.strip_suffix(suffix)?, shift)))
+            .unwrap_or((term, 0));
+        let n: u64 = digits.parse().ok()?;
+        sum.checked_add(n.checked_mul(1 << shift)?)
+    })
+}
+
+/// Value of --password-fd, exits if it is missing or not a number
+fn parse_fd(value: Option<&String>) -> u32 {
+    match value.and_then(|n| n.parse().ok()) {
+        Some(fd) => fd,
+        None => {
+            eprintln!("Option --password-fd needs a file descriptor number");
+            std::process::exit(phnx::PHNX_IO_ERROR);
+        }
+    }
+}
+
+/// Value of an option that takes a path, exits if it is missing
+fn parse_path(option: &str, value: Option<&String>) -> String {
+    match value {
+        Some(path) => path.clone(),
+        None => {
+            eprintln!("Option {} needs a path", option);
+            std::process::exit(phnx::PHNX_IO_ERROR);
         }
     }
+}
+
+/// Value of --slices, exits unless it is a number of slices a Golay set can have
+fn parse_slices(value: Option<&String>) -> phnx::CodecParams {
+    match value.and_then(|n| n.parse().ok()) {
+        Some(n) if phnx::SLICE_COUNTS.contains(&n) => phnx::CodecParams::golay(n).unwrap(),
+        _ => {
+            eprintln!(
+                "Option --slices needs a number of slices, one of {:?}",
+                phnx::SLICE_COUNTS
+            );
+            std::process::exit(phnx::PHNX_IO_ERROR);
+        }
+    }
+}
+
+/// Value of --codec: golay, the default, or rs:K+M for Reed-Solomon with K data and M parity
+/// slices. Exits if it is neither.
+fn parse_codec(value: Option<&String>) -> phnx::CodecParams {
+    let params = match value.map(String::as_str) {
+        Some("golay") => Ok(phnx::CodecParams::default()),
+        Some(v) => match v.strip_prefix("rs:").and_then(|v| v.split_once('+')) {
+            Some((k, m)) => match (k.parse(), m.parse()) {
+                (Ok(k), Ok(m)) => phnx::CodecParams::reed_solomon(k, m),
+                _ => Err(phnx::PhnxError::Format(format!("Cannot parse {}", v))),
+            },
+            None => Err(phnx::PhnxError::Format(format!("Unknown codec {}", v))),
+        },
+        None => Err(phnx::PhnxError::Format("No codec given".to_string())),
+    };
+    params.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        eprintln!("Option --codec needs golay, or rs:K+M for K data and M parity slices");
+        std::process::exit(phnx::PHNX_IO_ERROR);
+    })
+}
+
+/// Reads the password, and again if confirm is set, exits if the two attempts differ
+fn prompt_password(reader: &mut impl BufRead, label: &str, confirm: bool) -> String {
+    if !confirm {
+        return prompt_line(reader, &format!("Enter {}: ", label));
+    }
+    let first_attempt = prompt_line(reader, &format!("Enter {}      : ", label));
+    let second_attempt = prompt_line(reader, &format!("Enter {} again: ", label));
+    if first_attempt != second_attempt {
+        eprintln!("Passwords don't match");
+        std::process::exit(phnx::PHNX_WRONG_PASSWORD);
+    }
+    first_attempt
+}
+
+/// Prints prompt and reads a line. The terminal does not echo the line ending either, so it
+/// is printed here.
+fn prompt_line(reader: &mut impl BufRead, prompt: &str) -> String {
+    eprint!("{}", prompt);
+    io::stderr().flush().ok();
+    let mut line = String::new();
+    reader.read_line(&mut line).ok();
+    eprintln!();
+    trim_line_ending(line)
+}
+
+fn trim_line_ending(mut line: String) -> String {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    line
+}
+
+/// Turns off echo through the C library while the password is typed. Only the local modes are
+/// touched, the fourth tcflag_t of struct termios on Linux and the BSDs alike, where ECHO and
+/// TCSANOW have the same values too.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+mod echo {
+    use std::ffi::c_int;
+    use std::os::fd::AsRawFd;
+
+    /// unsigned long on Apple systems, unsigned int elsewhere
+    #[cfg(target_vendor = "apple")]
+    type TcFlag = std::ffi::c_ulong;
+    #[cfg(not(target_vendor = "apple"))]
+    type TcFlag = std::ffi::c_uint;
+
+    const LOCAL_MODES: usize = 3;
+    const ECHO: TcFlag = 0o10;
+    const TCSANOW: c_int = 0;
+
+    /// Room for struct termios of any of these systems
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    struct Termios([TcFlag; 64]);
+
+    extern "C" {
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, actions: c_int, termios: *const Termios) -> c_int;
+    }
+
+    /// Puts echo back when dropped
+    pub struct EchoOff {
+        fd: c_int,
+        saved: Option<Termios>,
+    }
+
+    /// Turns echo off if terminal is one, otherwise does nothing
+    pub fn off(terminal: &impl AsRawFd) -> EchoOff {
+        let fd = terminal.as_raw_fd();
+        let mut termios = Termios([0; 64]);
+        // SAFETY: termios is larger than what the C library writes
+        if unsafe { tcgetattr(fd, &mut termios) } != 0 {
+            return EchoOff { fd, saved: None };
+        }
+        let saved = termios;
+        termios.0[LOCAL_MODES] &= !ECHO;
+        // SAFETY: termios holds what tcgetattr() wrote, with echo turned off
+        unsafe { tcsetattr(fd, TCSANOW, &termios) };
+        EchoOff {
+            fd,
+            saved: Some(saved),
+        }
+    }
+
+    impl Drop for EchoOff {
+        fn drop(&mut self) {
+            if let Some(saved) = &self.saved {
+                // SAFETY: saved holds what tcgetattr() wrote
+                unsafe { tcsetattr(self.fd, TCSANOW, saved) };
+            }
+        }
+    }
+}
+
+/// Elsewhere the password is read with echo left on
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+mod echo {
+    pub struct EchoOff;
 
-    if ok_ct + fail_ct > 1 {
-        eprintln!("{} files, {} errors", ok_ct + fail_ct, fail_ct);
+    pub fn off<T>(_terminal: &T) -> EchoOff {
+        EchoOff
     }
-    std::process::exit(last_error_code);
 }
@@ -0,0 +1,319 @@
+//! Attributes of an encoded file: permissions, ownership, times, and extended attributes.
+//!
+//! They are read from the original when encoding and stored encrypted in the trailer, and put
+//! back on the decoded file. Each is optional, since not every system has all of them, and
+//! putting one back is best effort: a user other than root cannot give a file away, and not
+//! every file system takes extended attributes.
+
+use std::fs::{self, File, FileTimes};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::PhnxError;
+
+const TAG_MODE: u8 = 1;
+const TAG_UID: u8 = 2;
+const TAG_GID: u8 = 3;
+const TAG_MODIFIED: u8 = 4;
+const TAG_ACCESSED: u8 = 5;
+const TAG_XATTR: u8 = 6;
+const TAG_NAME: u8 = 7;
+
+/// Attributes of a file, each None or empty if unknown or not to be kept
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// Permission bits, including setuid, setgid, and sticky
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    /// Extended attributes as name and value
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Name of the original file without its directory, kept when the slices are named
+    /// otherwise so that it does not show
+    pub name: Option<String>,
+}
+
+impl FileMetadata {
+    /// Reads the attributes of the file at path, whose metadata is given
+    pub fn read(path: &str, metadata: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(metadata.mode() & 0o7777), Some(metadata.uid()), Some(metadata.gid()))
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (None, None, None);
+
+        FileMetadata {
+            mode,
+            uid,
+            gid,
+            modified: metadata.modified().ok(),
+            accessed: metadata.accessed().ok(),
+            xattrs: xattr::list(path),
+            name: None,
+        }
+    }
+
+    /// Puts the attributes back on the file at path, returning a warning for each that could
+    /// not be. Extended attributes go first and times last, since the others change the
+    /// change time and giving a file away clears its setuid and setgid bits.
+    pub fn apply(&self, path: &str) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut warn = |what: &str, e: io::Error| {
+            warnings.push(format!("Cannot restore {} of {}: {}", what, path, e))
+        };
+        for (name, value) in &self.xattrs {
+            if let Err(e) = xattr::set(path, name, value) {
+                warn(&format!("attribute {}", String::from_utf8_lossy(name)), e);
+            }
+        }
+        // Opened before the permissions may take write access away, which setting the times
+        // needs
+        let file = match File::options().write(true).open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn("attributes", e);
+                return warnings;
+            }
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{fchown, MetadataExt, PermissionsExt};
+            // Only tried when it changes something, so that restoring one's own files needs
+            // no privileges
+            if let Ok(current) = file.metadata() {
+                let uid = self.uid.filter(|&uid| uid != current.uid());
+                let gid = self.gid.filter(|&gid| gid != current.gid());
+                if uid.is_some() || gid.is_some() {
+                    if let Err(e) = fchown(&file, uid, gid) {
+                        warn("owner", e);
+                    }
+                }
+            }
+            if let Some(mode) = self.mode {
+                if let Err(e) = file.set_permissions(fs::Permissions::from_mode(mode)) {
+                    warn("permissions", e);
+                }
+            }
+        }
+
+        let mut times = FileTimes::new();
+        if let Some(modified) = self.modified {
+            times = times.set_modified(modified);
+        }
+        if let Some(accessed) = self.accessed {
+            times = times.set_accessed(accessed);
+        }
+        if let Err(e) = file.set_times(times) {
+            warn("times", e);
+        }
+        warnings
+    }
+
+    /// Every attribute as a tag byte, a 32-bit little-endian length, and the value. Readers
+    /// skip tags they do not know.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn put(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+            out.push(tag);
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+
+        let mut out = Vec::new();
+        for (tag, id) in [(TAG_MODE, self.mode), (TAG_UID, self.uid), (TAG_GID, self.gid)] {
+            if let Some(id) = id {
+                put(&mut out, tag, &id.to_le_bytes());
+            }
+        }
+        for (tag, time) in [(TAG_MODIFIED, self.modified), (TAG_ACCESSED, self.accessed)] {
+            if let Some(time) = time {
+                let (secs, nanos) = time_to_parts(time);
+                put(&mut out, tag, &[&secs.to_le_bytes()[..], &nanos.to_le_bytes()].concat());
+            }
+        }
+        for (name, value) in &self.xattrs {
+            let mut entry = (name.len() as u32).to_le_bytes().to_vec();
+            entry.extend_from_slice(name);
+            entry.extend_from_slice(value);
+            put(&mut out, TAG_XATTR, &entry);
+        }
+        if let Some(name) = &self.name {
+            put(&mut out, TAG_NAME, name.as_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PhnxError> {
+        let damaged = || PhnxError::Format("File metadata is malformed".to_string());
+        let mut metadata = FileMetadata::default();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            if rest.len() < 5 {
+                return Err(damaged());
+            }
+            let tag = rest[0];
+            let len = u32::from_le_bytes(rest[1..5].try_into().unwrap()) as usize;
+            if rest.len() - 5 < len {
+                return Err(damaged());
+            }
+            let value = &rest[5..5 + len];
+            rest = &rest[5 + len..];
+
+            let id = || value.try_into().map(u32::from_le_bytes).map_err(|_| damaged());
+            let time = || {
+                if len != 12 {
+                    return Err(damaged());
+                }
+                let secs = i64::from_le_bytes(value[..8].try_into().unwrap());
+                let nanos = u32::from_le_bytes(value[8..].try_into().unwrap());
+                Ok(time_from_parts(secs, nanos))
+            };
+            match tag {
+                TAG_MODE => metadata.mode = Some(id()?),
+                TAG_UID => metadata.uid = Some(id()?),
+                TAG_GID => metadata.gid = Some(id()?),
+                TAG_MODIFIED => metadata.modified = time()?,
+                TAG_ACCESSED => metadata.accessed = time()?,
+                TAG_XATTR => {
+                    let name_len = value
+                        .get(..4)
+                        .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                        .filter(|&n| n <= len - 4)
+                        .ok_or_else(damaged)?;
+                    let (name, value) = value[4..].split_at(name_len);
+                    metadata.xattrs.push((name.to_vec(), value.to_vec()));
+                }
+                TAG_NAME => {
+                    metadata.name = Some(String::from_utf8(value.to_vec()).map_err(|_| damaged())?)
+                }
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Seconds and nanoseconds since the Unix epoch, seconds rounded down for earlier times
+pub(crate) fn time_to_parts(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+
+/// Inverse of time_to_parts(), None if the system cannot represent the time
+pub(crate) fn time_from_parts(secs: i64, nanos: u32) -> Option<SystemTime> {
+    let whole = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+    };
+    whole?.checked_add(Duration::from_nanos(nanos as u64))
+}
+
+/// Extended attributes through the C library, which the standard library links anyway. Other
+/// systems than Linux have none as far as this is concerned.
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::ffi::{c_char, c_int, c_void, CString};
+    use std::io;
+
+    /// Buffer too small, on every Linux architecture
+    const ERANGE: i32 = 34;
+
+    extern "C" {
+        fn listxattr(path: *const c_char, list: *mut c_char, size: usize) -> isize;
+        fn getxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *mut c_void,
+            size: usize,
+        ) -> isize;
+        fn setxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *const c_void,
+            size: usize,
+            flags: c_int,
+        ) -> c_int;
+    }
+
+    /// Calls f with a buffer large enough for what it returns, retrying if that grew between
+    /// asking for the size and getting the contents
+    fn read_sized(f: impl Fn(*mut c_void, usize) -> isize) -> Option<Vec<u8>> {
+        loop {
+            let size = f(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return None;
+            }
+            let mut buffer = vec![0u8; size as usize];
+            let got = f(buffer.as_mut_ptr().cast(), buffer.len());
+            if got >= 0 {
+                buffer.truncate(got as usize);
+                return Some(buffer);
+            }
+            if io::Error::last_os_error().raw_os_error() != Some(ERANGE) {
+                return None;
+            }
+        }
+    }
+
+    /// Names and values of all extended attributes the file has and that can be read
+    pub fn list(path: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let Ok(path) = CString::new(path) else {
+            return Vec::new();
+        };
+        // SAFETY: the path is NUL-terminated, and the buffer is valid for size bytes
+        let names = read_sized(|buf, size| unsafe { listxattr(path.as_ptr(), buf.cast(), size) });
+        let mut xattrs = Vec::new();
+        for name in names.unwrap_or_default().split(|&b| b == 0).filter(|n| !n.is_empty()) {
+            let c_name = CString::new(name).unwrap();
+            // SAFETY: as above, and the name is NUL-terminated too
+            let value = read_sized(|buf, size| unsafe {
+                getxattr(path.as_ptr(), c_name.as_ptr(), buf, size)
+            });
+            if let Some(value) = value {
+                xattrs.push((name.to_vec(), value));
+            }
+        }
+        xattrs
+    }
+
+    pub fn set(path: &str, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let invalid = |_| io::Error::from(io::ErrorKind::InvalidInput);
+        let path = CString::new(path).map_err(invalid)?;
+        let name = CString::new(name).map_err(invalid)?;
+        // SAFETY: path and name are NUL-terminated, value is valid for its length
+        let result = unsafe {
+            setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::io;
+
+    pub fn list(_path: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        Vec::new()
+    }
+
+    pub fn set(_path: &str, _name: &[u8], _value: &[u8]) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
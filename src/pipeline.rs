@@ -0,0 +1,80 @@
+//! Chunk pipeline for encoding and decoding on several cores.
+//!
+//! A reader thread produces jobs, worker threads turn them into results, and the calling
+//! thread consumes the results in the order the jobs were produced. Only the work runs out of
+//! order, so the output is the same for any number of threads.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::PhnxError;
+
+/// Runs produce until it returns None, work on every job it produced, and consume on every
+/// result in order. With fewer than two threads all three run in turn on the calling thread.
+/// The first error any of them returns stops the pipeline and is returned.
+pub(crate) fn run<J: Send, T: Send>(
+    threads: usize,
+    mut produce: impl FnMut() -> Result<Option<J>, PhnxError> + Send,
+    work: impl Fn(J) -> Result<T, PhnxError> + Sync,
+    mut consume: impl FnMut(T) -> Result<(), PhnxError>,
+) -> Result<(), PhnxError> {
+    if threads < 2 {
+        while let Some(job) = produce()? {
+            consume(work(job)?)?;
+        }
+        return Ok(());
+    }
+
+    thread::scope(|scope| {
+        // Queues hold a few chunks per worker, so memory use does not grow with the input
+        let (job_tx, job_rx) = mpsc::sync_channel::<(u64, J)>(threads);
+        let (done_tx, done_rx) = mpsc::sync_channel(threads);
+
+        // Stops at the first error or once no worker is left to take jobs
+        let reader = scope.spawn(move || -> Result<(), PhnxError> {
+            let mut sequence = 0;
+            while let Some(job) = produce()? {
+                if job_tx.send((sequence, job)).is_err() {
+                    break;
+                }
+                sequence += 1;
+            }
+            Ok(())
+        });
+
+        // Workers own the only handles to the job queue, so the reader finds out when
+        // they are gone
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for _ in 0..threads {
+            let job_rx = Arc::clone(&job_rx);
+            let done_tx = done_tx.clone();
+            let work = &work;
+            scope.spawn(move || loop {
+                let job = job_rx.lock().unwrap().recv();
+                let Ok((sequence, job)) = job else {
+                    break;
+                };
+                let result = work(job);
+                let failed = result.is_err();
+                if done_tx.send((sequence, result)).is_err() || failed {
+                    break;
+                }
+            });
+        }
+        drop(job_rx);
+        drop(done_tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (sequence, result) in done_rx {
+            pending.insert(sequence, result);
+            while let Some(result) = pending.remove(&next) {
+                consume(result?)?;
+                next += 1;
+            }
+        }
+        reader.join().unwrap()
+    })
+}
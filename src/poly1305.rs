@@ -0,0 +1,186 @@
+/// Poly1305 one-time authenticator, 26-bit limbs. Each key must only be used for one message.
+pub struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+    buffer: [u8; 16],
+    leftover: usize,
+}
+
+#[inline]
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+impl Poly1305 {
+    /// Takes 16 bytes of r, clamped here, followed by 16 bytes of s
+    pub fn new(key: &[u8; 32]) -> Self {
+        Poly1305 {
+            r: [
+                le32(&key[0..]) & 0x3ffffff,
+                (le32(&key[3..]) >> 2) & 0x3ffff03,
+                (le32(&key[6..]) >> 4) & 0x3ffc0ff,
+                (le32(&key[9..]) >> 6) & 0x3f03fff,
+                (le32(&key[12..]) >> 8) & 0x00fffff,
+            ],
+            h: [0; 5],
+            pad: [
+                le32(&key[16..]),
+                le32(&key[20..]),
+                le32(&key[24..]),
+                le32(&key[28..]),
+            ],
+            buffer: [0; 16],
+            leftover: 0,
+        }
+    }
+
+    /// Adds 16-byte blocks to the accumulator, hibit is 2^128 shifted into the top limb,
+    /// or 0 for a final block that is already padded
+    fn blocks(&mut self, m: &[u8], hibit: u32) {
+        let [r0, r1, r2, r3, r4] = self.r.map(|r| r as u64);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let [mut h0, mut h1, mut h2, mut h3, mut h4] = self.h;
+
+        for block in m.chunks_exact(16) {
+            h0 += le32(&block[0..]) & 0x3ffffff;
+            h1 += (le32(&block[3..]) >> 2) & 0x3ffffff;
+            h2 += (le32(&block[6..]) >> 4) & 0x3ffffff;
+            h3 += (le32(&block[9..]) >> 6) & 0x3ffffff;
+            h4 += (le32(&block[12..]) >> 8) | hibit;
+
+            let [g0, g1, g2, g3, g4] = [h0, h1, h2, h3, h4].map(|h| h as u64);
+            let d0 = g0 * r0 + g1 * s4 + g2 * s3 + g3 * s2 + g4 * s1;
+            let mut d1 = g0 * r1 + g1 * r0 + g2 * s4 + g3 * s3 + g4 * s2;
+            let mut d2 = g0 * r2 + g1 * r1 + g2 * r0 + g3 * s4 + g4 * s3;
+            let mut d3 = g0 * r3 + g1 * r2 + g2 * r1 + g3 * r0 + g4 * s4;
+            let mut d4 = g0 * r4 + g1 * r3 + g2 * r2 + g3 * r1 + g4 * r0;
+
+            h0 = (d0 & 0x3ffffff) as u32;
+            d1 += d0 >> 26;
+            h1 = (d1 & 0x3ffffff) as u32;
+            d2 += d1 >> 26;
+            h2 = (d2 & 0x3ffffff) as u32;
+            d3 += d2 >> 26;
+            h3 = (d3 & 0x3ffffff) as u32;
+            d4 += d3 >> 26;
+            h4 = (d4 & 0x3ffffff) as u32;
+            h0 += (d4 >> 26) as u32 * 5;
+            h1 += h0 >> 26;
+            h0 &= 0x3ffffff;
+        }
+        self.h = [h0, h1, h2, h3, h4];
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        if self.leftover > 0 {
+            let take = std::cmp::min(16 - self.leftover, data.len());
+            self.buffer[self.leftover..self.leftover + take].copy_from_slice(&data[..take]);
+            self.leftover += take;
+            data = &data[take..];
+            if self.leftover < 16 {
+                return;
+            }
+            let block = self.buffer;
+            self.blocks(&block, 1 << 24);
+            self.leftover = 0;
+        }
+        let full = data.len() & !15;
+        self.blocks(&data[..full], 1 << 24);
+        let rest = &data[full..];
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.leftover = rest.len();
+    }
+
+    pub fn finalize(mut self) -> [u8; 16] {
+        if self.leftover > 0 {
+            let mut block = [0u8; 16];
+            block[..self.leftover].copy_from_slice(&self.buffer[..self.leftover]);
+            block[self.leftover] = 1;
+            self.blocks(&block, 0);
+        }
+
+        // Fully carry h
+        let [mut h0, mut h1, mut h2, mut h3, mut h4] = self.h;
+        h2 += h1 >> 26;
+        h1 &= 0x3ffffff;
+        h3 += h2 >> 26;
+        h2 &= 0x3ffffff;
+        h4 += h3 >> 26;
+        h3 &= 0x3ffffff;
+        h0 += (h4 >> 26) * 5;
+        h4 &= 0x3ffffff;
+        h1 += h0 >> 26;
+        h0 &= 0x3ffffff;
+
+        // Compute h - p, keep it if it did not borrow
+        let mut g0 = h0.wrapping_add(5);
+        let mut g1 = h1.wrapping_add(g0 >> 26);
+        g0 &= 0x3ffffff;
+        let mut g2 = h2.wrapping_add(g1 >> 26);
+        g1 &= 0x3ffffff;
+        let mut g3 = h3.wrapping_add(g2 >> 26);
+        g2 &= 0x3ffffff;
+        let g4 = h4.wrapping_add(g3 >> 26).wrapping_sub(1 << 26);
+        g3 &= 0x3ffffff;
+
+        let mask = (g4 >> 31).wrapping_sub(1);
+        h0 = (h0 & !mask) | (g0 & mask);
+        h1 = (h1 & !mask) | (g1 & mask);
+        h2 = (h2 & !mask) | (g2 & mask);
+        h3 = (h3 & !mask) | (g3 & mask);
+        h4 = (h4 & !mask) | (g4 & mask);
+
+        // h % 2^128, plus s
+        let words = [
+            h0 | (h1 << 26),
+            (h1 >> 6) | (h2 << 20),
+            (h2 >> 12) | (h3 << 14),
+            (h3 >> 18) | (h4 << 8),
+        ];
+        let mut tag = [0u8; 16];
+        let mut carry = 0u64;
+        for i in 0..4 {
+            let f = words[i] as u64 + self.pad[i] as u64 + carry;
+            tag[i * 4..i * 4 + 4].copy_from_slice(&(f as u32).to_le_bytes());
+            carry = f >> 32;
+        }
+        tag
+    }
+}
+
+/// Compares tags without branching on their contents
+pub fn tags_equal(a: &[u8; 16], b: &[u8; 16]) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn self_test() -> bool {
+    // RFC 8439, section 2.5.2
+    let key: [u8; 32] = [
+        0x85, 0xd6, 0xbe, 0x78, 0x57, 0x55, 0x6d, 0x33, 0x7f, 0x44, 0x52, 0xfe, 0x42, 0xd5, 0x06,
+        0xa8, 0x01, 0x03, 0x80, 0x8a, 0xfb, 0x0d, 0xb2, 0xfd, 0x4a, 0xbf, 0xf6, 0xaf, 0x41, 0x49,
+        0xf5, 0x1b,
+    ];
+    let expected: [u8; 16] = [
+        0xa8, 0x06, 0x1d, 0xc1, 0x30, 0x51, 0x36, 0xc6, 0xc2, 0x2b, 0x8b, 0xaf, 0x0c, 0x01, 0x27,
+        0xa9,
+    ];
+    let message = b"Cryptographic Forum Research Group";
+
+    // All at once, and byte by byte through the leftover buffer
+    let mut whole = Poly1305::new(&key);
+    whole.update(message);
+    let mut pieces = Poly1305::new(&key);
+    for b in message.chunks(1) {
+        pieces.update(b);
+    }
+    let observed = [whole.finalize(), pieces.finalize()];
+    if observed.iter().any(|tag| !tags_equal(tag, &expected)) {
+        eprintln!("Poly1305 self-test failed");
+        eprintln!("Expected {:02x?}", expected);
+        eprintln!("Observed {:02x?}", observed[0]);
+        return false;
+    }
+
+    true
+}
@@ -1,773 +1,1213 @@
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::time::SystemTime;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::thread;
 
+use crate::codec::{CodecParams, DEFAULT_SLICE_COUNT, MAX_SLICE_COUNT};
 use crate::crc32c::Crc32c;
-use crate::golay::GolayCode;
+use crate::error::PhnxError;
+use crate::kdf::KdfParams;
+use crate::key::Key;
+use crate::metadata::FileMetadata;
+use crate::random;
+use crate::slices::{
+    arrange_slices, regenerate_slices, slice_letter, verify_slices, DamageFill, Decoder, Encoder,
+    Report, SliceErrors, SliceHeader, SliceHealth, SliceNote, SliceSet,
+};
 use crate::speck;
 
-pub const PHNX_OK: i32 = 0;
-pub const PHNX_IO_ERROR: i32 = 1;
-pub const PHNX_WRONG_PASSWORD: i32 = 2;
-pub const PHNX_UNCORRECTABLE_ERROR: i32 = 3;
-pub const PHNX_FORMAT_ERROR: i32 = 4;
-
-fn golay_read_and_decode<R: Read>(
-    buffer: &mut [u8],
-    bytes_to_read: usize,
-    slices: &mut [Option<R>; 8],
-    gc: &mut GolayCode,
-) -> i32 {
-    let mut block_offset = 0;
-    while block_offset < bytes_to_read {
-        // Read 3 bytes from each available slice into [u8; 24] laid out as 8x3
-        let mut eighttriplets = [0u8; 24];
-        for i in 0..8 {
-            if let Some(ref mut f) = slices[i] {
-                let base = i * 3;
-                if f.read_exact(&mut eighttriplets[base..base + 3]).is_err() {
-                    eprintln!("\nError reading from slice {}", (b'A' + i as u8) as char);
-                    return PHNX_IO_ERROR;
-                }
-            }
+type SliceReaders = Vec<Option<BufReader<File>>>;
+type SliceFileSet = SliceSet<BufReader<File>>;
+
+/// Command line settings that affect how each file is processed
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Encrypt in place in the legacy format, without error correction
+    pub compatibility_mode: bool,
+    /// Keep a decoded file even if parts of it could not be recovered, filling those
+    /// parts as given. Otherwise such a file is removed.
+    pub keep_damaged: Option<DamageFill>,
+    /// Worker threads for encoding and decoding slices, 0 for one per core
+    pub threads: usize,
+    /// Codec to encode new sets with and how many slices to split them into. Decoding takes
+    /// both from the slice headers.
+    pub codec: CodecParams,
+    /// Include files and directories whose names start with a dot in recursive mode
+    pub hidden: bool,
+    /// Neither store permissions, ownership, times, and extended attributes of files when
+    /// encoding, nor restore them when decoding
+    pub skip_metadata: bool,
+    /// Name new slices with a random id instead of the file name, which is then stored
+    /// encrypted with the data and given back when decoding
+    pub hide_name: bool,
+    /// Path to decode into instead of the name the file had, or to name new slices after
+    /// instead of the file
+    pub output: Option<String>,
+    /// Directory to write slices or decoded files into instead of the one they come from
+    pub outdir: Option<String>,
+    /// Replace files that are in the way of the output. Otherwise they are left alone and
+    /// the file that would replace them fails.
+    pub force: bool,
+    /// Further passwords that decode new slices as well as the one they are encoded with.
+    /// Each gets a key slot with a key derived on its own.
+    pub extra_passwords: Vec<Vec<u8>>,
+    /// Key, such as the hash of a key file, to use as is instead of the password. New slices
+    /// get a key slot it opens without key derivation, legacy files take it as their key.
+    pub key: Option<[u8; 32]>,
+    /// Further keys that decode new slices as is, each in a key slot of its own
+    pub extra_keys: Vec<[u8; 32]>,
+    /// X25519 public keys whose private keys decode new slices. With an empty password they
+    /// alone do, so that encoding needs no secret.
+    pub recipients: Vec<[u8; 32]>,
+    /// X25519 private key to decode slices made for its public key with, instead of the
+    /// password
+    pub private_key: Option<[u8; 32]>,
+    /// Rekeying adds the new passwords and recipients to those that open a set already,
+    /// instead of replacing them
+    pub keep_slots: bool,
+    /// Plaintext bytes to decode instead of the whole file, cut off at its end
+    pub range: Option<Range<u64>>,
+}
+
+impl Options {
+    pub(crate) fn worker_threads(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
         }
+    }
+}
 
-        // Convert to qwords for BMI2 path
-        let qwords = [
-            u64::from_le_bytes([
-                eighttriplets[0],
-                eighttriplets[1],
-                eighttriplets[2],
-                eighttriplets[3],
-                eighttriplets[4],
-                eighttriplets[5],
-                eighttriplets[6],
-                eighttriplets[7],
-            ]),
-            u64::from_le_bytes([
-                eighttriplets[8],
-                eighttriplets[9],
-                eighttriplets[10],
-                eighttriplets[11],
-                eighttriplets[12],
-                eighttriplets[13],
-                eighttriplets[14],
-                eighttriplets[15],
-            ]),
-            u64::from_le_bytes([
-                eighttriplets[16],
-                eighttriplets[17],
-                eighttriplets[18],
-                eighttriplets[19],
-                eighttriplets[20],
-                eighttriplets[21],
-                eighttriplets[22],
-                eighttriplets[23],
-            ]),
-        ];
-
-        let mut twelvebytes = [0u8; 12];
-        let mut twelvebytes_q = [0u64; 2];
-
-        for i in 0..8 {
-            let codeword;
-            #[cfg(target_feature = "bmi2")]
-            {
-                #[cfg(target_arch = "x86_64")]
-                use std::arch::x86_64::_pext_u64;
-                #[cfg(target_arch = "x86")]
-                use std::arch::x86::_pext_u64;
-
-                let mask: u64 = 0x0101010101010101u64 << i;
-                let extracted_lo = unsafe { _pext_u64(qwords[0], mask) };
-                let extracted_mid = unsafe { _pext_u64(qwords[1], mask) };
-                let extracted_hi = unsafe { _pext_u64(qwords[2], mask) };
-                codeword = (extracted_lo | (extracted_mid << 8) | (extracted_hi << 16)) as u32;
-            }
-            #[cfg(not(target_feature = "bmi2"))]
-            {
-                let mut cw = 0u32;
-                for k in 0..8 {
-                    for t in 0..3 {
-                        if eighttriplets[k * 3 + t] & (1 << i) != 0 {
-                            cw |= 1 << (k * 3 + t);
-                        }
-                    }
-                }
-                codeword = cw;
-            }
+/// Row of dots on stderr that fills up with o's as work gets done
+struct ProgressBar {
+    total_notches: u32,
+    notches_shown: u32,
+}
 
-            let x = gc.decode(codeword);
+impl ProgressBar {
+    fn new(length: u64) -> Self {
+        let total_notches = 10 + (64 - length.leading_zeros());
+        eprint!(" ");
+        for _ in 0..total_notches {
+            eprint!(".");
+        }
+        eprint!(" \r ");
+        ProgressBar {
+            total_notches,
+            notches_shown: 0,
+        }
+    }
 
-            #[cfg(target_feature = "bmi2")]
-            {
-                #[cfg(target_arch = "x86_64")]
-                use std::arch::x86_64::_pdep_u64;
-                #[cfg(target_arch = "x86")]
-                use std::arch::x86::_pdep_u64;
-
-                let mask: u64 = 0x0101010101010101u64 << i;
-                let halfmask: u64 = mask & 0xffffffff;
-                twelvebytes_q[0] |= unsafe { _pdep_u64(x as u64, mask) };
-                twelvebytes_q[1] |= unsafe { _pdep_u64((x >> 8) as u64, halfmask) };
-            }
-            #[cfg(not(target_feature = "bmi2"))]
-            {
-                for j in 0..12 {
-                    if x & (1 << j) != 0 {
-                        twelvebytes[j] |= 1 << i;
-                    }
-                }
-            }
+    fn update(&mut self, done: u64, length: u64) {
+        let total_notches = self.total_notches;
+        let notches_done = if length == 0 {
+            total_notches
+        } else {
+            (done as u128 * total_notches as u128 / length as u128) as u32
+        };
+        let notches_remaining = total_notches - notches_done.min(total_notches);
+        while total_notches - self.notches_shown > notches_remaining {
+            eprint!("o");
+            self.notches_shown += 1;
         }
+    }
 
-        #[cfg(target_feature = "bmi2")]
-        {
-            twelvebytes[..8].copy_from_slice(&twelvebytes_q[0].to_le_bytes());
-            twelvebytes[8..12].copy_from_slice(&twelvebytes_q[1].to_le_bytes()[..4]);
-        }
-
-        let end = std::cmp::min(block_offset + 12, buffer.len());
-        let copy_len = end - block_offset;
-        buffer[block_offset..block_offset + copy_len]
-            .copy_from_slice(&twelvebytes[..copy_len]);
-        block_offset += 12;
-    }
-    PHNX_OK
-}
-
-fn golay_encode_and_write<W: Write>(
-    data: &[u8],
-    data_size: usize,
-    slices: &mut [Option<W>; 8],
-    gc: &mut GolayCode,
-) -> i32 {
-    let mut block_offset = 0;
-    while block_offset < data_size {
-        // Pad with zeroes
-        let mut twelvebytes = [0u8; 12];
-        let copy_size = std::cmp::min(12, data_size - block_offset);
-        twelvebytes[..copy_size].copy_from_slice(&data[block_offset..block_offset + copy_size]);
-
-        let twelvebytes_q = [
-            u64::from_le_bytes([
-                twelvebytes[0],
-                twelvebytes[1],
-                twelvebytes[2],
-                twelvebytes[3],
-                twelvebytes[4],
-                twelvebytes[5],
-                twelvebytes[6],
-                twelvebytes[7],
-            ]),
-            u64::from_le_bytes([
-                twelvebytes[8],
-                twelvebytes[9],
-                twelvebytes[10],
-                twelvebytes[11],
-                0,
-                0,
-                0,
-                0,
-            ]),
-        ];
-
-        let mut eighttriplets = [0u8; 24];
-        let mut eighttriplets_q = [0u64; 3];
-
-        for i in 0..8 {
-            let x;
-            #[cfg(target_feature = "bmi2")]
-            {
-                #[cfg(target_arch = "x86_64")]
-                use std::arch::x86_64::_pext_u64;
-                #[cfg(target_arch = "x86")]
-                use std::arch::x86::_pext_u64;
-
-                let mask: u64 = 0x0101010101010101u64 << i;
-                let halfmask: u64 = mask & 0xffffffff;
-                let bits0to7 = unsafe { _pext_u64(twelvebytes_q[0], mask) };
-                let bits8to11 = unsafe { _pext_u64(twelvebytes_q[1], halfmask) };
-                x = (bits0to7 | (bits8to11 << 8)) as u32;
-            }
-            #[cfg(not(target_feature = "bmi2"))]
-            {
-                let mut val = 0u32;
-                for j in 0..12 {
-                    if twelvebytes[j] & (1 << i) != 0 {
-                        val |= 1 << j;
-                    }
-                }
-                x = val;
-            }
+    fn clear(&self) {
+        eprint!("\r ");
+        for _ in 0..self.total_notches {
+            eprint!(" ");
+        }
+        eprint!(" \r");
+    }
+}
+
+/// Legacy cryptolocker file operations, all done in place
+enum Legacy {
+    /// Encrypt and append the 16 byte suffix, rename to .encrypted
+    Encrypt,
+    /// Decrypt .encrypted file, check CRC32C from the suffix
+    DecryptCrc32c,
+    /// Decrypt .encrypted-XXXXXXXX file, where XXXXXXXX is the checksum
+    /// and the part of the filename before it is at given position
+    DecryptChecksum(u32, usize),
+}
 
-            let codeword = gc.encode(x);
+/// Returns name of the original file if filename ends with .phnx_[A-X]
+pub fn slice_base_name(filename: &str) -> Option<&str> {
+    let bytes = filename.as_bytes();
+    let last = slice_letter(MAX_SLICE_COUNT - 1) as u8;
+    if bytes.len() >= 7
+        && bytes[bytes.len() - 7..bytes.len() - 1] == *b".phnx_"
+        && (b'A'..=last).contains(&bytes[bytes.len() - 1])
+    {
+        Some(&filename[..filename.len() - 7])
+    } else {
+        None
+    }
+}
 
-            #[cfg(target_feature = "bmi2")]
-            {
-                #[cfg(target_arch = "x86_64")]
-                use std::arch::x86_64::_pdep_u64;
-                #[cfg(target_arch = "x86")]
-                use std::arch::x86::_pdep_u64;
-
-                let mask: u64 = 0x0101010101010101u64 << i;
-                eighttriplets_q[0] |=
-                    unsafe { _pdep_u64((codeword & 0xff) as u64, mask) };
-                eighttriplets_q[1] |=
-                    unsafe { _pdep_u64(((codeword >> 8) & 0xff) as u64, mask) };
-                eighttriplets_q[2] |=
-                    unsafe { _pdep_u64(((codeword >> 16) & 0xff) as u64, mask) };
-            }
-            #[cfg(not(target_feature = "bmi2"))]
-            {
-                for k in 0..8 {
-                    for t in 0..3 {
-                        if codeword & (1 << (k * 3 + t)) != 0 {
-                            eighttriplets[k * 3 + t] |= 1 << i;
-                        }
-                    }
+pub fn slice_filename(base_filename: &str, i: usize) -> String {
+    format!("{}.phnx_{}", base_filename, slice_letter(i))
+}
+
+/// Names of all slices of a set at once, such as name.phnx_[A-H]
+pub(crate) fn slice_pattern(base_filename: &str, slice_count: usize) -> String {
+    format!("{}.phnx_[A-{}]", base_filename, slice_letter(slice_count - 1))
+}
+
+/// Encodes, decodes, encrypts, or decrypts the file depending on its name. Slices get a key
+/// derived from password with a fresh salt, legacy files use the password, or the key in
+/// options, as the key.
+pub fn process_one_file(
+    filename: &str,
+    password: &[u8],
+    options: &Options,
+) -> Result<Report, PhnxError> {
+    if filename.is_empty() {
+        return Err(PhnxError::io(
+            "Empty filename",
+            io::Error::from(io::ErrorKind::InvalidInput),
+        ));
+    }
+
+    if let Some(base_filename) = slice_base_name(filename) {
+        return decode_slices(base_filename, password, options, |_| Ok(()));
+    }
+
+    if filename.len() >= 7 {
+        println!("Processing {}", filename);
+    }
+
+    // Check for .encrypted or .encrypted-XXXXXXXX
+    let key = match &options.key {
+        Some(key) => Key::new(key),
+        None => Key::from_password(password),
+    };
+    if filename.len() >= 10 {
+        if filename.ends_with(".encrypted") {
+            return legacy_process(filename, &key, Legacy::DecryptCrc32c, options);
+        }
+        if let Some(dot_pos) = filename.rfind(".encrypted-") {
+            let hex_part = &filename[dot_pos + 11..];
+            if !hex_part.is_empty() && hex_part.chars().all(|c| c.is_ascii_hexdigit()) {
+                if let Ok(cs) = u32::from_str_radix(hex_part, 16) {
+                    return legacy_process(
+                        filename,
+                        &key,
+                        Legacy::DecryptChecksum(cs, dot_pos),
+                        options,
+                    );
                 }
             }
         }
+    }
 
-        #[cfg(target_feature = "bmi2")]
+    if options.compatibility_mode {
+        if !options.extra_passwords.is_empty()
+            || !options.extra_keys.is_empty()
+            || !options.recipients.is_empty()
         {
-            eighttriplets[0..8].copy_from_slice(&eighttriplets_q[0].to_le_bytes());
-            eighttriplets[8..16].copy_from_slice(&eighttriplets_q[1].to_le_bytes());
-            eighttriplets[16..24].copy_from_slice(&eighttriplets_q[2].to_le_bytes());
-        }
-
-        // Write each slice
-        for i in 0..8 {
-            let base = i * 3;
-            if let Some(ref mut f) = slices[i] {
-                if f.write_all(&eighttriplets[base..base + 3]).is_err() {
-                    eprintln!("\nError writing slice {}", (b'A' + i as u8) as char);
-                    return PHNX_IO_ERROR;
-                }
-            }
+            return Err(PhnxError::Format(
+                "Compatibility mode encrypts for one password only".to_string(),
+            ));
         }
+        legacy_process(filename, &key, Legacy::Encrypt, options)
+    } else {
+        encode_slices(filename, &slice_base_for(filename, options)?, password, options)
+    }
+}
 
-        block_offset += 12;
+/// Base name for the slices of a file: the output given in options, else its own name or a
+/// random id if the name is to be hidden, in the output directory or next to the file
+pub(crate) fn slice_base_for(filename: &str, options: &Options) -> Result<String, PhnxError> {
+    if let Some(output) = &options.output {
+        return Ok(output.clone());
+    }
+    if !options.hide_name {
+        return Ok(place(filename, file_name(filename), options));
+    }
+    loop {
+        let id = format!("{:016x}", random::random_u64()?);
+        let base_filename = place(filename, &id, options);
+        if !Path::new(&slice_filename(&base_filename, 0)).exists() {
+            return Ok(base_filename);
+        }
     }
-    PHNX_OK
 }
 
-#[allow(unused_assignments)]
-pub fn process_one_file(
+/// Path of a file called name in the output directory, or next to path if there is none
+fn place(path: &str, name: &str, options: &Options) -> String {
+    let placed = match &options.outdir {
+        Some(dir) => Path::new(dir).join(name),
+        None => Path::new(path).with_file_name(name),
+    };
+    placed.to_string_lossy().into_owned()
+}
+
+/// Last name in a path
+pub(crate) fn file_name(path: &str) -> &str {
+    Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or(path)
+}
+
+/// Encodes a file into slices named after base_filename, storing its name in them if that
+/// differs. Slices only replace those of the same name once they are complete.
+pub(crate) fn encode_slices(
     filename: &str,
-    schedule: &[u64; 34],
-    compatibility_mode: bool,
-) -> i32 {
-    let mut check_checksum = false;
-    let mut expected_checksum: u32 = 0;
-    let mut check_crc32c = false;
-    let mut expected_crc32c: u32 = 0;
-    let mut append_suffix = true;
-    let mut nonce: u64 = 0;
-    let mut golay_encode = !compatibility_mode;
-    let mut golay_decode = false;
-    let mut slices_r: [Option<BufReader<File>>; 8] = [None, None, None, None, None, None, None, None];
-    let mut slices_w: [Option<BufWriter<File>>; 8] = [None, None, None, None, None, None, None, None];
-    let mut length: i64 = 0;
-    let mut remaining_length: i64 = 0;
-    let mut gc = GolayCode::new();
-
-    // p_offset: position of the last character in filename (like C++ p)
-    let fname_bytes = filename.as_bytes();
-    if fname_bytes.is_empty() {
-        eprintln!("Empty filename");
-        return PHNX_IO_ERROR;
-    }
-
-    let p = fname_bytes.len() - 1; // index of last char
-
-    // Check for .phnx_[A-H]
-    if p >= 6 {
-        let suffix_start = p - 6;
-        let suffix = &filename[suffix_start..];
-        if suffix.len() == 7
-            && suffix.starts_with(".phnx_")
-            && (b'A'..=b'H').contains(&suffix.as_bytes()[6])
-        {
-            let mut missing_ct = 0;
-            for i in 0..8 {
-                let mut slice_filename = filename.to_string();
-                let last = slice_filename.len() - 1;
-                unsafe {
-                    slice_filename.as_bytes_mut()[last] = b'A' + i as u8;
-                }
-                match File::open(&slice_filename) {
-                    Ok(f) => slices_r[i] = Some(BufReader::new(f)),
-                    Err(_) => {
-                        eprintln!("Cannot open {}", slice_filename);
-                        if missing_ct > 0 {
-                            eprintln!(
-                                "More than one slice is missing, not enough to recover"
-                            );
-                            return PHNX_UNCORRECTABLE_ERROR;
-                        }
-                        missing_ct += 1;
-                    }
-                }
-            }
-            golay_decode = true;
-            golay_encode = false;
-            check_crc32c = true;
-            append_suffix = false;
-        }
+    base_filename: &str,
+    password: &[u8],
+    options: &Options,
+) -> Result<Report, PhnxError> {
+    let input =
+        File::open(filename).map_err(|e| PhnxError::io(format!("Cannot open {}", filename), e))?;
+    let metadata = input
+        .metadata()
+        .map_err(|e| PhnxError::io("Cannot determine file length", e))?;
+    let length = metadata.len();
+
+    let mut file_metadata = if options.skip_metadata {
+        FileMetadata::default()
+    } else {
+        FileMetadata::read(filename, &metadata)
+    };
+    if base_filename != filename {
+        println!(
+            "Writing {}",
+            slice_pattern(base_filename, options.codec.slice_count())
+        );
+    }
+    if file_name(base_filename) != file_name(filename) {
+        file_metadata.name = Some(file_name(filename).to_string());
     }
 
-    if p >= 6 {
-        if golay_decode {
-            let mut display_name = filename.to_string();
-            let last = display_name.len() - 1;
-            unsafe {
-                display_name.as_bytes_mut()[last] = b'[';
-            }
-            println!("Processing {}A-H]", display_name);
-        } else {
-            println!("Processing {}", filename);
-        }
+    let (slices, pending) = create_slices(base_filename, options)?;
+    let mut encoder = new_encoder(password, options)?;
+    if file_metadata != FileMetadata::default() {
+        encoder = encoder.metadata(file_metadata);
     }
 
-    // Check for .encrypted or .encrypted-XXXXXXXX
-    // Store the position where we found the hex suffix start (for renaming)
-    let mut hex_suffix_rename_end: Option<usize> = None;
+    let mut bar = ProgressBar::new(length);
+    let result = {
+        let mut progress = |done, total| bar.update(done, total);
+        encoder.progress(&mut progress).encode(input, slices)
+    };
+    bar.clear();
+    let report = result?;
+    finish_slices(base_filename, options.codec.slice_count(), pending)?;
+    Ok(report)
+}
 
-    if !golay_decode && p >= 9 {
-        if filename.ends_with(".encrypted") {
-            check_crc32c = true;
-            append_suffix = false;
-            golay_encode = false;
-        } else {
-            // Check for .encrypted-XXXXXXXX
-            // Find the dash after .encrypted
-            if let Some(dot_pos) = filename.rfind(".encrypted-") {
-                let hex_part = &filename[dot_pos + 11..];
-                if !hex_part.is_empty() && hex_part.chars().all(|c| c.is_ascii_hexdigit()) {
-                    if let Ok(cs) = u32::from_str_radix(hex_part, 16) {
-                        expected_checksum = cs;
-                        check_checksum = true;
-                        append_suffix = false;
-                        golay_encode = false;
-                        hex_suffix_rename_end = Some(dot_pos);
-                    }
-                }
-            }
+/// Encodes everything read from input, such as standard input, into slices named after
+/// base_filename. Input is never seeked, so it can be a pipe.
+pub fn encode_stream<R: Read + Send>(
+    input: R,
+    base_filename: &str,
+    password: &[u8],
+    options: &Options,
+) -> Result<Report, PhnxError> {
+    println!(
+        "Processing standard input into {}",
+        slice_pattern(base_filename, options.codec.slice_count())
+    );
+    let (slices, pending) = create_slices(base_filename, options)?;
+    let report = new_encoder(password, options)?.encode_stream(input, slices)?;
+    finish_slices(base_filename, options.codec.slice_count(), pending)?;
+    Ok(report)
+}
+
+/// What opens a new set, in the order its key slots are written
+enum NewSlot<'a> {
+    Password(&'a [u8]),
+    Key(&'a [u8; 32]),
+    Recipient(&'a [u8; 32]),
+}
+
+/// Encoder for a new set that password and every key, extra password, and recipient in
+/// options open, each password with a fresh salt. An empty password is left out if anything
+/// else opens the set.
+pub(crate) fn new_encoder<'a>(
+    password: &[u8],
+    options: &Options,
+) -> Result<Encoder<'a>, PhnxError> {
+    let passwords = std::iter::once(password)
+        .filter(|p| !p.is_empty())
+        .chain(options.extra_passwords.iter().map(Vec::as_slice));
+    let keys = options.key.iter().chain(&options.extra_keys);
+    let mut slots = passwords
+        .map(NewSlot::Password)
+        .chain(keys.map(NewSlot::Key))
+        .chain(options.recipients.iter().map(NewSlot::Recipient));
+    let mut encoder = match slots.next() {
+        Some(NewSlot::Password(p)) => Encoder::with_password(p, KdfParams::new()?)?,
+        Some(NewSlot::Key(key)) => Encoder::new(&Key::new(key)),
+        Some(NewSlot::Recipient(recipient)) => Encoder::for_recipient(recipient)?,
+        None => Encoder::with_password(password, KdfParams::new()?)?,
+    };
+    for slot in slots {
+        encoder = match slot {
+            NewSlot::Password(p) => encoder.add_password(p, KdfParams::new()?)?,
+            NewSlot::Key(key) => encoder.add_key(&Key::new(key)),
+            NewSlot::Recipient(recipient) => encoder.add_recipient(recipient)?,
+        };
+    }
+    Ok(encoder
+        .codec(options.codec)
+        .threads(options.worker_threads()))
+}
+
+/// Creates temporary files for the slices of a new set, to be put in place by
+/// finish_slices(). Any slice of that name is in the way unless options say to replace it.
+pub(crate) fn create_slices(
+    base_filename: &str,
+    options: &Options,
+) -> Result<(Vec<BufWriter<File>>, PendingFiles), PhnxError> {
+    // Encoder would refuse the codec too, but only after the files were created
+    options.codec.check()?;
+    for i in 0..MAX_SLICE_COUNT {
+        refuse_existing(&slice_filename(base_filename, i), options)?;
+    }
+    let mut pending = PendingFiles::default();
+    let slices = (0..options.codec.slice_count())
+        .map(|i| pending.create(&slice_filename(base_filename, i)).map(BufWriter::new))
+        .collect::<Result<_, _>>()?;
+    Ok((slices, pending))
+}
+
+/// Renames complete slices into place
+pub(crate) fn finish_slices(
+    base_filename: &str,
+    slice_count: usize,
+    pending: PendingFiles,
+) -> Result<(), PhnxError> {
+    pending.commit()?;
+    // Slices past the last one are left from a larger set of the same name, which the new one
+    // has replaced. Kept, they would be taken for the set when decoding.
+    for i in slice_count..MAX_SLICE_COUNT {
+        let name = slice_filename(base_filename, i);
+        if fs::remove_file(&name).is_ok() {
+            eprintln!("Removed {}, left from an earlier set", name);
         }
     }
+    Ok(())
+}
 
-    // Open files
-    let mut f: Option<File>;
+/// Fails if something is at path, unless options say to replace it
+fn refuse_existing(path: &str, options: &Options) -> Result<(), PhnxError> {
+    if !options.force && fs::symlink_metadata(path).is_ok() {
+        return Err(PhnxError::io(
+            format!("Not replacing {} without --force", path),
+            io::Error::from(io::ErrorKind::AlreadyExists),
+        ));
+    }
+    Ok(())
+}
 
-    if golay_decode {
-        // Read suffix (2 blocks = 48 bytes = 6 bytes per slice)
-        for i in 0..8 {
-            if let Some(ref mut s) = slices_r[i] {
-                if s.seek(SeekFrom::End(-6)).is_err() {
-                    eprintln!("\nError seeking in slice {}", (b'A' + i as u8) as char);
-                    return PHNX_IO_ERROR;
+/// Files written under temporary names next to where they go, so that a half-written or
+/// rejected one never replaces what was there. commit() renames them into place, and those
+/// still pending when dropped are removed.
+#[derive(Default)]
+pub(crate) struct PendingFiles {
+    /// Temporary name and final name of each file
+    names: Vec<(String, String)>,
+}
+
+impl PendingFiles {
+    /// Creates a hidden file with a random name in the directory of target
+    fn create(&mut self, target: &str) -> Result<File, PhnxError> {
+        loop {
+            let id = random::random_u64()?;
+            let name = format!(".{}.{:016x}.tmp", file_name(target), id);
+            let temporary = Path::new(target).with_file_name(name).to_string_lossy().into_owned();
+            match File::options().write(true).create_new(true).open(&temporary) {
+                Ok(file) => {
+                    self.names.push((temporary, target.to_string()));
+                    return Ok(file);
                 }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(PhnxError::io(format!("Cannot create {}", target), e)),
             }
         }
-        let mut suffix_bytes = [0u8; 24];
-        let ret = golay_read_and_decode(&mut suffix_bytes, 24, &mut slices_r, &mut gc);
-        if ret != PHNX_OK {
-            return ret;
+    }
+
+    fn commit(mut self) -> Result<(), PhnxError> {
+        while let Some((temporary, target)) = self.names.first() {
+            rename(temporary, target)?;
+            self.names.remove(0);
         }
-        for i in 0..8 {
-            if let Some(ref mut s) = slices_r[i] {
-                if s.seek(SeekFrom::Start(0)).is_err() {
-                    return PHNX_IO_ERROR;
-                }
-            }
+        Ok(())
+    }
+}
+
+impl Drop for PendingFiles {
+    fn drop(&mut self) {
+        for (temporary, _) in &self.names {
+            fs::remove_file(temporary).ok();
         }
+    }
+}
 
-        // Extract suffix
-        let suffix_0 = u64::from_le_bytes(suffix_bytes[0..8].try_into().unwrap());
-        let suffix_1 = u64::from_le_bytes(suffix_bytes[8..16].try_into().unwrap());
-        let suffix_2 = u64::from_le_bytes(suffix_bytes[16..24].try_into().unwrap());
-
-        // Decrypt suffix with nonce=-1, counter=-1, -2
-        let nonce_ctr_m1 = [0xffffffffffffffffu64, 0xffffffffffffffffu64];
-        let nonce_ctr_m2 = [0xffffffffffffffffu64, 0xfffffffffffffffeu64];
-        let gamma1 = speck::speck_encrypt(&nonce_ctr_m1, schedule);
-        let gamma2 = speck::speck_encrypt(&nonce_ctr_m2, schedule);
-
-        let s0 = suffix_0 ^ gamma1[0];
-        let s1 = suffix_1 ^ gamma1[1];
-        let s2 = suffix_2 ^ gamma2[0];
-
-        let crc32c0 = s0 as u32;
-        let crc32c1 = (s0 >> 32) as u32;
-        if crc32c0 != crc32c1 {
-            eprintln!("CRC mismatch, wrong password?");
-            return PHNX_WRONG_PASSWORD;
-        }
-        check_crc32c = true;
-        expected_crc32c = crc32c0;
-        nonce = s1;
-        length = s2 as i64;
-        remaining_length = length;
-
-        // Create output file (trim .phnx_X)
-        let base_filename = &filename[..filename.len() - 7];
-        match File::create(base_filename) {
-            Ok(file) => f = Some(file),
-            Err(_) => {
-                eprintln!("Cannot create {}", base_filename);
-                return PHNX_IO_ERROR;
-            }
+/// Decodes a set into a temporary file, which replaces the output only once its checksum and
+/// tags matched and accept() took the report. Damaged files kept as options say replace it
+/// too.
+pub(crate) fn decode_slices(
+    base_filename: &str,
+    password: &[u8],
+    options: &Options,
+    accept: impl FnOnce(&Report) -> Result<(), PhnxError>,
+) -> Result<Report, PhnxError> {
+    let (set, _, warnings) = open_slice_set(base_filename)?;
+    println!("Processing {}", slice_pattern(base_filename, set.slices.len()));
+    print_warnings(&warnings);
+    let mut pending = PendingFiles::default();
+    let mut created = None;
+    let result = decode_set(set, password, options, |metadata| {
+        let output_filename = output_filename(base_filename, metadata, options)?;
+        if output_filename != base_filename {
+            println!("Writing {}", output_filename);
         }
-    } else {
-        if golay_encode {
-            match File::open(filename) {
-                Ok(file) => f = Some(file),
-                Err(_) => {
-                    eprintln!("Cannot open {}", filename);
-                    return PHNX_IO_ERROR;
-                }
+        refuse_existing(&output_filename, options)?;
+        let output = pending.create(&output_filename)?;
+        created = Some(output_filename);
+        Ok(output)
+    });
+    let Some(output_filename) = created else {
+        return result;
+    };
+    match &result {
+        Ok(report) => {
+            accept(report)?;
+            if let (Some(metadata), false) = (&report.metadata, options.skip_metadata) {
+                print_warnings(&metadata.apply(&pending.names[0].0));
             }
-        } else {
-            match OpenOptions::new().read(true).write(true).open(filename) {
-                Ok(file) => f = Some(file),
-                Err(_) => {
-                    eprintln!("Cannot open {}", filename);
-                    return PHNX_IO_ERROR;
-                }
+        }
+        Err(_) => {
+            report_partial_output(&output_filename, options);
+            if options.keep_damaged.is_none() {
+                return result;
             }
         }
+    }
+    pending.commit()?;
+    result
+}
 
-        // Determine file length
-        let file_ref = f.as_mut().unwrap();
-        length = match file_ref.seek(SeekFrom::End(0)) {
-            Ok(len) => len as i64,
-            Err(_) => {
-                eprintln!("Cannot determine file length");
-                return PHNX_IO_ERROR;
+/// Where a set decodes to: the output given in options, else the name stored in the slices or
+/// that of the slices without .phnx_X, in the output directory or next to the slices
+fn output_filename(
+    base_filename: &str,
+    metadata: Option<&FileMetadata>,
+    options: &Options,
+) -> Result<String, PhnxError> {
+    if let Some(output) = &options.output {
+        return Ok(output.clone());
+    }
+    match metadata.and_then(|m| m.name.as_deref()) {
+        Some(name) => {
+            // Anything but a plain name could put the file elsewhere
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0'])
+            {
+                return Err(PhnxError::Format(format!(
+                    "Stored file name {:?} is not a plain name, give --output",
+                    name
+                )));
             }
-        };
-        if file_ref.seek(SeekFrom::Start(0)).is_err() {
-            return PHNX_IO_ERROR;
+            Ok(place(base_filename, name, options))
         }
-        remaining_length = length;
-        nonce = length as u64;
+        None => Ok(place(base_filename, file_name(base_filename), options)),
+    }
+}
 
-        if check_crc32c && !golay_decode {
-            if length < 16 {
-                eprintln!("\nNo suffix in {}", filename);
-                return PHNX_FORMAT_ERROR;
-            }
-            // Read the suffix
-            let file_ref = f.as_mut().unwrap();
-            if file_ref.seek(SeekFrom::Start((length - 16) as u64)).is_err() {
-                return PHNX_IO_ERROR;
-            }
-            let mut suffix_buf = [0u8; 16];
-            if file_ref.read_exact(&mut suffix_buf).is_err() {
-                eprintln!("\nError reading suffix from {}", filename);
-                return PHNX_IO_ERROR;
-            }
-            if file_ref.seek(SeekFrom::Start(0)).is_err() {
-                return PHNX_IO_ERROR;
-            }
+/// Decodes the set the given slice belongs to into output, such as standard output, which is
+/// only ever written to. What was written cannot be taken back when decoding fails, so unless
+/// options say to keep damaged data, output stops before the first chunk that fails.
+pub fn decode_stream<W: Write>(
+    filename: &str,
+    password: &[u8],
+    options: &Options,
+    output: W,
+) -> Result<Report, PhnxError> {
+    let base_filename = slice_base_name(filename).ok_or_else(|| {
+        PhnxError::Format(format!("{} is not a .phnx_[A-X] slice", filename))
+    })?;
+    let (set, _, warnings) = open_slice_set(base_filename)?;
+    eprintln!("Processing {}", slice_pattern(base_filename, set.slices.len()));
+    print_warnings(&warnings);
+    decode_set(set, password, options, |_| Ok(output))
+}
 
-            let s0 = u64::from_le_bytes(suffix_buf[0..8].try_into().unwrap());
-            let s1 = u64::from_le_bytes(suffix_buf[8..16].try_into().unwrap());
+/// Decodes a set into the output made by create_output, which is only called once the
+/// password checks out and is given the metadata stored in the set, and prints what was
+/// corrected or lost
+pub(crate) fn decode_set<W: Write>(
+    set: SliceFileSet,
+    password: &[u8],
+    options: &Options,
+    create_output: impl FnOnce(Option<&FileMetadata>) -> Result<W, PhnxError>,
+) -> Result<Report, PhnxError> {
+    let decoder = open_decoder(set.slices, password, options)?;
+    let decoder = match &options.range {
+        Some(range) => decoder.range(range.clone())?,
+        None => decoder,
+    };
+    let output = create_output(decoder.metadata())?;
+
+    let mut bar = ProgressBar::new(decoder.length());
+    let result = {
+        let mut progress = |done, total| bar.update(done, total);
+        let mut decoder = decoder.progress(&mut progress).threads(options.worker_threads());
+        if let Some(fill) = options.keep_damaged {
+            decoder = decoder.keep_damaged(fill);
+        }
+        decoder.decode(output)
+    };
+    bar.clear();
+
+    let report = match &result {
+        Ok(report) => report,
+        Err(PhnxError::Damaged(report)) | Err(PhnxError::AuthFailed(report)) => report,
+        Err(_) => return result,
+    };
+    if report.stats.corrected_codewords != 0 || report.stats.uncorrectable_codewords != 0 {
+        eprintln!("{}", report.stats);
+        print_slice_errors(&report.slice_errors);
+        print_damage_map("Damaged bytes", &report.damaged);
+    }
+    print_damage_map("Unauthenticated bytes", &report.unauthenticated);
+    result
+}
 
-            // Decrypt suffix on nonce -1 and counter -1
-            let all_ones = [0xffffffffffffffffu64, 0xffffffffffffffffu64];
-            let gamma = speck::speck_encrypt(&all_ones, schedule);
-            let s0 = s0 ^ gamma[0];
-            let s1 = s1 ^ gamma[1];
+/// Decoder of the slices with the private key or key in options if there is one, else with
+/// password
+fn open_decoder<'a, R: Read + Seek>(
+    slices: Vec<Option<R>>,
+    password: &[u8],
+    options: &Options,
+) -> Result<Decoder<'a, R>, PhnxError> {
+    match (&options.private_key, &options.key) {
+        (Some(private_key), _) => Decoder::with_private_key(private_key, slices),
+        (None, Some(key)) => Decoder::new(&Key::new(key), slices),
+        (None, None) => Decoder::with_password(password, slices),
+    }
+}
 
-            let crc32c0 = s0 as u32;
-            let crc32c1 = (s0 >> 32) as u32;
-            if crc32c0 != crc32c1 {
-                eprintln!("CRC mismatch, maybe wrong password?");
-                return PHNX_WRONG_PASSWORD;
-            }
-            expected_crc32c = crc32c0;
-            nonce = s1;
-            remaining_length = length - 16;
-        }
+/// Says whether a decoded file that failed its checks is kept or discarded
+fn report_partial_output(base_filename: &str, options: &Options) {
+    match options.keep_damaged {
+        Some(DamageFill::Zeroes) => eprintln!(
+            "Kept {}, unrecoverable bytes are zero-filled, unauthenticated ones left as decoded",
+            base_filename
+        ),
+        Some(DamageFill::Marker) => eprintln!(
+            "Kept {}, unrecoverable bytes are marked, unauthenticated ones left as decoded",
+            base_filename
+        ),
+        None => eprintln!("Discarded partially recovered {}", base_filename),
     }
+}
 
-    if append_suffix || golay_encode {
-        let mut random_number = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_micros() as u64;
+/// Prints every byte range in the list, inclusive
+fn print_damage_map(label: &str, damaged: &[Range<u64>]) {
+    for r in damaged {
+        eprintln!(
+            "{} 0x{:x}-0x{:x} ({} bytes)",
+            label,
+            r.start,
+            r.end - 1,
+            r.end - r.start
+        );
+    }
+}
 
-        #[cfg(target_feature = "rdrand")]
-        unsafe {
-            #[cfg(target_arch = "x86_64")]
-            std::arch::x86_64::_rdrand64_step(&mut random_number);
-            #[cfg(target_arch = "x86")]
-            std::arch::x86::_rdrand64_step(&mut random_number);
+/// Prints how many bits were corrected in each slice, and at which offsets
+fn print_slice_errors(slice_errors: &[SliceErrors]) {
+    const CLUSTERS_SHOWN: usize = 4;
+    for (i, errors) in slice_errors.iter().enumerate() {
+        if errors.bits == 0 {
+            continue;
+        }
+        let mut ranges: Vec<String> = errors
+            .clusters
+            .iter()
+            .take(CLUSTERS_SHOWN)
+            .map(|c| {
+                if c.first_offset == c.last_offset {
+                    format!("0x{:x}", c.first_offset)
+                } else {
+                    format!("0x{:x}-0x{:x}", c.first_offset, c.last_offset)
+                }
+            })
+            .collect();
+        if errors.clusters.len() > CLUSTERS_SHOWN {
+            ranges.push(format!("{} more", errors.clusters.len() - CLUSTERS_SHOWN));
         }
+        eprintln!(
+            "Slice {}: {} corrected bits at offsets {}",
+            slice_letter(i),
+            errors.bits,
+            ranges.join(", ")
+        );
+    }
+}
 
-        nonce ^= random_number;
+pub(crate) fn print_warnings(warnings: &[String]) {
+    for warning in warnings {
+        eprintln!("{}", warning);
     }
+}
 
-    // Progress bar
-    let mut total_notches: u32 = 10;
-    {
-        let mut x = remaining_length;
-        while x > 0 {
-            x >>= 1;
-            total_notches += 1;
+/// Opens all slices of a set and puts them in place by their headers, returns them with their
+/// common length and what was wrong with them. A slice that cannot be opened, whose length
+/// differs from the others, or that belongs to another set, is left out. Until the headers
+/// are read the number of slices is unknown, so every name up to .phnx_X is tried.
+pub(crate) fn open_slice_set(
+    base_filename: &str,
+) -> Result<(SliceFileSet, u64, Vec<String>), PhnxError> {
+    let mut slices: SliceReaders = (0..MAX_SLICE_COUNT).map(|_| None).collect();
+    let mut lengths = [None; MAX_SLICE_COUNT];
+    for i in 0..MAX_SLICE_COUNT {
+        let name = slice_filename(base_filename, i);
+        if let Ok((len, f)) = File::open(&name).and_then(|f| Ok((f.metadata()?.len(), f))) {
+            slices[i] = Some(BufReader::new(f));
+            lengths[i] = Some(len);
         }
     }
-    eprint!(" ");
-    for _ in 0..total_notches {
-        eprint!(".");
+
+    // Slices of a set have equal length, whichever is most common must be right
+    let slice_length = lengths
+        .iter()
+        .flatten()
+        .copied()
+        .max_by_key(|&len| (lengths.iter().filter(|&&l| l == Some(len)).count(), len))
+        .ok_or_else(|| PhnxError::Uncorrectable("No slices found".to_string()))?;
+    let mut mismatched = Vec::new();
+    for i in 0..MAX_SLICE_COUNT {
+        if let Some(len) = lengths[i] {
+            if len != slice_length {
+                mismatched.push(format!(
+                    "Slice {} is {} bytes long, expected {}",
+                    slice_letter(i),
+                    len,
+                    slice_length
+                ));
+                slices[i] = None;
+            }
+        }
     }
-    eprint!(" \r ");
-    let mut notches_shown: u32 = 0;
 
-    let mut nonce_and_counter: [u64; 8] = [
-        nonce, nonce, nonce, nonce, 0, 1, 2, 3,
-    ];
+    let mut set = arrange_slices(slices)?;
+    // Sets without headers always have 8 slices
+    if set.header.is_none() {
+        set.slices.truncate(DEFAULT_SLICE_COUNT);
+    }
+    let mut warnings: Vec<String> = (0..set.slices.len())
+        .filter(|&i| lengths[i].is_none())
+        .map(|i| format!("Cannot open {}", slice_filename(base_filename, i)))
+        .collect();
+    warnings.append(&mut mismatched);
+    let journal = rekey_journal_name(base_filename);
+    if fs::symlink_metadata(&journal).is_ok() {
+        warnings.push(format!(
+            "{} is left from an interrupted rekey, running rekey again rolls it back",
+            journal
+        ));
+    }
+    for note in &set.notes {
+        warnings.push(match *note {
+            SliceNote::Moved { from, to } => format!(
+                "{} is slice {} of the set, using it as such",
+                slice_filename(base_filename, from),
+                slice_letter(to)
+            ),
+            SliceNote::ForeignSet(i) => format!(
+                "{} belongs to a different set, ignored",
+                slice_filename(base_filename, i)
+            ),
+            SliceNote::Duplicate { from, of } => format!(
+                "{} is another copy of slice {}, ignored",
+                slice_filename(base_filename, from),
+                slice_letter(of)
+            ),
+            SliceNote::HeaderDamaged(i) => {
+                format!("{} has a damaged header", slice_filename(base_filename, i))
+            }
+        });
+    }
+    Ok((set, slice_length, warnings))
+}
 
-    let mut crc32c_before = Crc32c::new();
-    let mut crc32c_after = Crc32c::new();
+/// Reads every codeword of the set the given slice belongs to and reports how many errors
+/// each slice has. Nothing is decrypted or written, so no password is needed.
+pub fn verify_one_file(filename: &str) -> Result<SliceHealth, PhnxError> {
+    let base_filename = slice_base_name(filename).ok_or_else(|| {
+        PhnxError::Format(format!("{} is not a .phnx_[A-X] slice", filename))
+    })?;
+    let (mut set, slice_length, warnings) = open_slice_set(base_filename)?;
+    println!("Verifying {}", slice_pattern(base_filename, set.slices.len()));
+    print_warnings(&warnings);
+
+    let mut bar = ProgressBar::new(slice_length);
+    let result = {
+        let mut progress = |done, total| bar.update(done, total);
+        verify_slices(
+            &mut set.slices,
+            slice_length,
+            set.header.as_ref(),
+            Some(&mut progress),
+        )
+    };
+    bar.clear();
+    let health = result?;
+
+    eprintln!("{}", health.stats);
+    for i in 0..health.missing.len() {
+        if health.missing[i] {
+            eprintln!("Slice {}: missing", slice_letter(i));
+        }
+    }
+    print_slice_errors(&health.slice_errors);
+    print_damage_map("Uncorrectable stream bytes", &health.damaged);
+    if health.stats.uncorrectable_codewords != 0 {
+        return Err(PhnxError::Uncorrectable(format!(
+            "{} uncorrectable codewords",
+            health.stats.uncorrectable_codewords
+        )));
+    }
+    Ok(health)
+}
 
-    if golay_encode {
-        for i in 0..8 {
-            let mut slice_filename = filename.to_string();
-            slice_filename.push_str(".phnx_");
-            slice_filename.push((b'A' + i as u8) as char);
-            match File::create(&slice_filename) {
-                Ok(file) => slices_w[i] = Some(BufWriter::new(file)),
-                Err(_) => {
-                    eprintln!("Cannot create {}", slice_filename);
-                    return PHNX_IO_ERROR;
-                }
+/// Regenerates missing, truncated, misnamed, or damaged slices of the set the given slice
+/// belongs to, rewriting only those. Slices are never decrypted, so no password is needed.
+pub fn repair_one_file(filename: &str) -> Result<(), PhnxError> {
+    let base_filename = slice_base_name(filename).ok_or_else(|| {
+        PhnxError::Format(format!("{} is not a .phnx_[A-X] slice", filename))
+    })?;
+    let (set, slice_length, warnings) = open_slice_set(base_filename)?;
+    println!("Repairing {}", slice_pattern(base_filename, set.slices.len()));
+    print_warnings(&warnings);
+    // Regenerating would overwrite a slice of another set, which may be its only copy
+    for note in &set.notes {
+        if let SliceNote::ForeignSet(i) = *note {
+            return Err(PhnxError::Format(format!(
+                "{} belongs to a different set, move it away before repairing",
+                slice_filename(base_filename, i)
+            )));
+        }
+    }
+    let header = set.header;
+    let mut slices = set.slices;
+
+    // First pass regenerates slices that cannot be read or were found under another name,
+    // and finds damaged ones
+    let mut rewrite: Vec<bool> = slices.iter().map(Option::is_none).collect();
+    for note in &set.notes {
+        if let SliceNote::Moved { to, .. } = *note {
+            rewrite[to] = true;
+        }
+    }
+    // Regenerated slices go to temporary files, removed if either pass fails
+    let mut pending = PendingFiles::default();
+    let health = regenerate_pass(
+        &mut pending,
+        base_filename,
+        &mut slices,
+        slice_length,
+        header,
+        &rewrite,
+    )?;
+
+    // Second pass rewrites slices that had bit errors or a damaged header, if any
+    let damaged: Vec<bool> = (0..slices.len())
+        .map(|i| !rewrite[i] && (health.slice_errors[i].bits != 0 || health.header_damaged[i]))
+        .collect();
+    if damaged.contains(&true) {
+        for (i, slice) in slices.iter_mut().enumerate() {
+            if let Some(s) = slice {
+                s.seek(SeekFrom::Start(0)).map_err(|e| {
+                    PhnxError::io(format!("Error seeking in slice {}", slice_letter(i)), e)
+                })?;
             }
         }
+        regenerate_pass(
+            &mut pending,
+            base_filename,
+            &mut slices,
+            slice_length,
+            header,
+            &damaged,
+        )?;
     }
 
-    while remaining_length > 0 {
-        let mut buffer = [0u8; 16 * 4 * 12 * 100];
-        let chunk_size = std::cmp::min(remaining_length as usize, buffer.len());
-
-        if golay_decode {
-            let ret =
-                golay_read_and_decode(&mut buffer, chunk_size, &mut slices_r, &mut gc);
-            if ret != PHNX_OK {
-                return ret;
-            }
-        } else {
-            let file_ref = f.as_mut().unwrap();
-            let position = file_ref.stream_position().unwrap_or(0);
-            if file_ref.read_exact(&mut buffer[..chunk_size]).is_err() {
-                eprintln!("\nError reading {}", filename);
-                return PHNX_IO_ERROR;
-            }
-            if !golay_encode {
-                if file_ref.seek(SeekFrom::Start(position)).is_err() {
-                    return PHNX_IO_ERROR;
-                }
-            }
+    // Slices are renamed over only once they are no longer read
+    drop(slices);
+    pending.commit()?;
+
+    for i in 0..rewrite.len() {
+        if rewrite[i] {
+            eprintln!("Slice {} regenerated", slice_letter(i));
+        } else if damaged[i] && health.header_damaged[i] {
+            eprintln!("Slice {} had a damaged header, rewritten", slice_letter(i));
+        } else if damaged[i] {
+            eprintln!(
+                "Slice {} had {} damaged bits, rewritten",
+                slice_letter(i),
+                health.slice_errors[i].bits
+            );
         }
+    }
+    if !rewrite.contains(&true) && !damaged.contains(&true) {
+        eprintln!("All slices are intact");
+    }
+    Ok(())
+}
 
-        // Update CRC32C before processing
-        crc32c_before.update_slice(&buffer[..chunk_size]);
-
-        // CTR mode encryption
-        let mut offset = 0;
-        while offset < chunk_size {
-            let keystream = speck::speck_encrypt4(&nonce_and_counter, schedule);
-            nonce_and_counter[4] += 4;
-            nonce_and_counter[5] += 4;
-            nonce_and_counter[6] += 4;
-            nonce_and_counter[7] += 4;
-
-            // XOR buffer with keystream in interleaved order [0,4,1,5,2,6,3,7]
-            const KS_ORDER: [usize; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
-            for (block_idx, &ks_idx) in KS_ORDER.iter().enumerate() {
-                for i in 0..8 {
-                    let buf_pos = offset + block_idx * 8 + i;
-                    if buf_pos < chunk_size {
-                        buffer[buf_pos] ^= (keystream[ks_idx] >> (i * 8)) as u8;
-                    }
-                }
-            }
+/// Writes regenerated slices into temporary files that pending renames over them later
+fn regenerate_pass(
+    pending: &mut PendingFiles,
+    base_filename: &str,
+    slices: &mut SliceReaders,
+    slice_length: u64,
+    header: Option<SliceHeader>,
+    rewrite: &[bool],
+) -> Result<SliceHealth, PhnxError> {
+    let mut outputs: Vec<Option<BufWriter<File>>> = rewrite.iter().map(|_| None).collect();
+    for (i, &rewritten) in rewrite.iter().enumerate() {
+        if rewritten {
+            let file = pending.create(&slice_filename(base_filename, i))?;
+            outputs[i] = Some(BufWriter::new(file));
+        }
+    }
 
-            offset += 16 * 4;
+    let mut bar = ProgressBar::new(slice_length);
+    let result = {
+        let mut progress = |done, total| bar.update(done, total);
+        regenerate_slices(
+            slices,
+            slice_length,
+            header.as_ref(),
+            &mut outputs,
+            Some(&mut progress),
+        )
+    };
+    bar.clear();
+    result
+}
+
+/// Changes who can decode the set the given slice belongs to. It is opened with password, or
+/// the key or private key in options, and new_password, the extra passwords and keys, and the
+/// recipients in options take the place of what opened it before, as for new slices. The data
+/// is not decrypted and keeps its data key: only the trailer of every slice is overwritten in
+/// place, after the old ones are saved in a journal that puts them back if that is
+/// interrupted. A journal left by an earlier rekey of the set is rolled back first.
+pub fn rekey_one_file(
+    filename: &str,
+    password: &[u8],
+    new_password: &[u8],
+    options: &Options,
+) -> Result<(), PhnxError> {
+    let base_filename = slice_base_name(filename).ok_or_else(|| {
+        PhnxError::Format(format!("{} is not a .phnx_[A-X] slice", filename))
+    })?;
+    roll_back_rekey(base_filename)?;
+    let (set, _, warnings) = open_slice_set(base_filename)?;
+    println!("Rekeying {}", slice_pattern(base_filename, set.slices.len()));
+    print_warnings(&warnings);
+    // Every slice gets the new trailer under its own name, or it would disagree with the rest
+    let misplaced = set.notes.iter().any(|note| !matches!(note, SliceNote::HeaderDamaged(_)));
+    if misplaced || set.slices.iter().any(Option::is_none) {
+        return Err(PhnxError::Format(format!(
+            "Slices of {} are missing or out of place, repair them before rekeying",
+            base_filename
+        )));
+    }
+    let decoder = open_decoder(set.slices, password, options)?;
+    // The key that opened the set is not carried over unless the old slots are kept
+    let new_options = Options {
+        key: None,
+        ..options.clone()
+    };
+    let trailer = decoder.rekey(&new_encoder(new_password, &new_options)?, options.keep_slots)?;
+    drop(decoder);
+
+    // Old trailers are safely in the journal before any slice is touched, so that an
+    // interruption cannot leave some slices with the old key slots and some with the new
+    let mut old_trailers = Vec::new();
+    for i in 0..trailer.slices.len() {
+        let name = slice_filename(base_filename, i);
+        let bytes = read_trailer(&name, trailer.offset)
+            .map_err(|e| PhnxError::io(format!("Error reading from {}", name), e))?;
+        old_trailers.push(bytes);
+    }
+    let journal = rekey_journal_name(base_filename);
+    write_journal(&journal, &serialize_journal(trailer.offset, &old_trailers))
+        .map_err(|e| PhnxError::io(format!("Error writing {}", journal), e))?;
+    let result = write_trailers(base_filename, trailer.offset, &trailer.slices);
+    if let Err(e) = result {
+        // The journal stays behind if the old trailers cannot be put back either
+        if write_trailers(base_filename, trailer.offset, &old_trailers).is_ok() {
+            fs::remove_file(&journal).ok();
         }
+        return Err(e);
+    }
+    fs::remove_file(&journal)
+        .map_err(|e| PhnxError::io(format!("Error removing {}", journal), e))
+}
 
-        // Update CRC32C after processing
-        crc32c_after.update_slice(&buffer[..chunk_size]);
+/// Start of a rekey journal
+const REKEY_JOURNAL_MAGIC: &[u8; 13] = b"PHNX rekey 1\n";
 
-        if golay_encode {
-            let ret =
-                golay_encode_and_write(&buffer, chunk_size, &mut slices_w, &mut gc);
-            if ret != PHNX_OK {
-                return ret;
-            }
-        } else {
-            let file_ref = f.as_mut().unwrap();
-            if file_ref.write_all(&buffer[..chunk_size]).is_err() {
-                eprintln!("\nError writing {}", filename);
-                return PHNX_IO_ERROR;
+/// Hidden file next to the slices that holds their old trailers while rekey overwrites them
+fn rekey_journal_name(base_filename: &str) -> String {
+    let name = format!(".{}.phnx_rekey", file_name(base_filename));
+    Path::new(base_filename).with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Journal contents: the magic, the offset where the trailers start, the length and bytes of
+/// the old trailer of every slice starting with A, then CRC32C of all that, all numbers
+/// little-endian
+fn serialize_journal(offset: u64, trailers: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = REKEY_JOURNAL_MAGIC.to_vec();
+    out.extend_from_slice(&offset.to_le_bytes());
+    for trailer in trailers {
+        out.extend_from_slice(&(trailer.len() as u64).to_le_bytes());
+        out.extend_from_slice(trailer);
+    }
+    let mut crc32c = Crc32c::new();
+    crc32c.update_slice(&out);
+    out.extend_from_slice(&crc32c.finalize().to_le_bytes());
+    out
+}
+
+/// Offset and old trailers of a journal. None if it is incomplete, which means it was never
+/// synced and no slice was touched.
+fn parse_journal(bytes: &[u8]) -> Option<(u64, Vec<Vec<u8>>)> {
+    let (body, crc) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+    let mut crc32c = Crc32c::new();
+    crc32c.update_slice(body);
+    if crc32c.finalize().to_le_bytes() != crc || !body.starts_with(REKEY_JOURNAL_MAGIC) {
+        return None;
+    }
+    let mut rest = &body[REKEY_JOURNAL_MAGIC.len()..];
+    let mut take = |n: usize| {
+        let (bytes, tail) = rest.split_at_checked(n)?;
+        rest = tail;
+        Some(bytes)
+    };
+    let offset = u64::from_le_bytes(take(8)?.try_into().unwrap());
+    let mut trailers = Vec::new();
+    while let Some(len) = take(8) {
+        let len = usize::try_from(u64::from_le_bytes(len.try_into().unwrap())).ok()?;
+        trailers.push(take(len)?.to_vec());
+    }
+    Some((offset, trailers))
+}
+
+/// Creates the journal and syncs it and its directory, so that it is found after a crash
+fn write_journal(journal: &str, bytes: &[u8]) -> io::Result<()> {
+    let mut file = File::options().write(true).create_new(true).open(journal)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    let dir = Path::new(journal).parent().filter(|dir| !dir.as_os_str().is_empty());
+    File::open(dir.unwrap_or(Path::new(".")))?.sync_all()
+}
+
+/// Puts back the old trailers an interrupted rekey of the set left in its journal, then
+/// removes the journal. Does nothing if there is none.
+fn roll_back_rekey(base_filename: &str) -> Result<(), PhnxError> {
+    let journal = rekey_journal_name(base_filename);
+    let bytes = match fs::read(&journal) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(PhnxError::io(format!("Error reading {}", journal), e)),
+    };
+    if let Some((offset, trailers)) = parse_journal(&bytes) {
+        println!("Rolling back an interrupted rekey of {}", base_filename);
+        write_trailers(base_filename, offset, &trailers)?;
+    }
+    fs::remove_file(&journal)
+        .map_err(|e| PhnxError::io(format!("Error removing {}", journal), e))
+}
+
+/// Bytes of the slice from offset to its end
+fn read_trailer(slice: &str, offset: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(slice)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Writes every trailer over its slice from offset on, and cuts the slice off after it
+fn write_trailers(base_filename: &str, offset: u64, trailers: &[Vec<u8>]) -> Result<(), PhnxError> {
+    for (i, trailer) in trailers.iter().enumerate() {
+        let name = slice_filename(base_filename, i);
+        let write = || -> io::Result<()> {
+            let mut file = OpenOptions::new().write(true).open(&name)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(trailer)?;
+            file.set_len(offset + trailer.len() as u64)?;
+            file.sync_data()
+        };
+        write().map_err(|e| PhnxError::io(format!("Error rewriting {}", name), e))?;
+    }
+    Ok(())
+}
+
+fn legacy_process(
+    filename: &str,
+    key: &Key,
+    mode: Legacy,
+    options: &Options,
+) -> Result<Report, PhnxError> {
+    // Checked first, since the file is rewritten in place before it is renamed
+    let new_filename = match mode {
+        Legacy::Encrypt => format!("{}.encrypted", filename),
+        Legacy::DecryptCrc32c => filename[..filename.len() - 10].to_string(),
+        Legacy::DecryptChecksum(_, dot_pos) => filename[..dot_pos].to_string(),
+    };
+    refuse_existing(&new_filename, options)?;
+
+    let schedule = key.schedule();
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(filename)
+        .map_err(|e| PhnxError::io(format!("Cannot open {}", filename), e))?;
+
+    let length = f
+        .seek(SeekFrom::End(0))
+        .map_err(|e| PhnxError::io("Cannot determine file length", e))?;
+    let mut remaining_length = length;
+    let mut nonce = length;
+    let mut expected_crc32c = 0u32;
+
+    match mode {
+        Legacy::Encrypt => nonce ^= random::random_u64()?,
+        Legacy::DecryptCrc32c => {
+            if length < 16 {
+                return Err(PhnxError::Format(format!("No suffix in {}", filename)));
             }
-        }
+            // Read the suffix
+            let mut suffix_buf = [0u8; 16];
+            f.seek(SeekFrom::Start(length - 16))
+                .and_then(|_| f.read_exact(&mut suffix_buf))
+                .map_err(|e| PhnxError::io(format!("Error reading suffix from {}", filename), e))?;
 
-        remaining_length -= chunk_size as i64;
+            // Decrypt suffix on nonce -1 and counter -1
+            let all_ones = [0xffffffffffffffffu64, 0xffffffffffffffffu64];
+            let gamma = speck::speck_encrypt(&all_ones, schedule);
+            let s0 = u64::from_le_bytes(suffix_buf[0..8].try_into().unwrap()) ^ gamma[0];
+            let s1 = u64::from_le_bytes(suffix_buf[8..16].try_into().unwrap()) ^ gamma[1];
 
-        // Update progress bar
-        let done = length - remaining_length;
-        let notches_remaining =
-            total_notches - (done as u32 * total_notches / length as u32).min(total_notches);
-        if total_notches - notches_shown > notches_remaining {
-            let notches_to_show = total_notches - notches_shown - notches_remaining;
-            for _ in 0..notches_to_show {
-                eprint!("o");
-                notches_shown += 1;
+            let crc32c0 = s0 as u32;
+            let crc32c1 = (s0 >> 32) as u32;
+            if crc32c0 != crc32c1 {
+                return Err(PhnxError::WrongPassword);
             }
+            expected_crc32c = crc32c0;
+            nonce = s1;
+            remaining_length = length - 16;
         }
+        Legacy::DecryptChecksum(..) => {}
     }
+    f.seek(SeekFrom::Start(0))
+        .map_err(|e| PhnxError::io(format!("Error seeking in {}", filename), e))?;
 
-    // Clear progress bar
-    eprint!("\r ");
-    for _ in 0..total_notches {
-        eprint!(" ");
+    let plaintext_length = remaining_length;
+    let mut nonce_and_counter: [u64; 8] = [nonce, nonce, nonce, nonce, 0, 1, 2, 3];
+    let mut crc32c_before = Crc32c::new();
+    let mut crc32c_after = Crc32c::new();
+
+    let mut bar = ProgressBar::new(remaining_length);
+    let mut buffer = [0u8; 16 * 4 * 12 * 100];
+    while remaining_length > 0 {
+        let chunk_size = std::cmp::min(remaining_length, buffer.len() as u64) as usize;
+        let chunk = &mut buffer[..chunk_size];
+
+        let position = f.stream_position().unwrap_or(0);
+        f.read_exact(chunk)
+            .map_err(|e| PhnxError::io(format!("Error reading {}", filename), e))?;
+        f.seek(SeekFrom::Start(position))
+            .map_err(|e| PhnxError::io(format!("Error seeking in {}", filename), e))?;
+
+        crc32c_before.update_slice(chunk);
+        speck::speck_ctr(chunk, &mut nonce_and_counter, schedule);
+        crc32c_after.update_slice(chunk);
+
+        f.write_all(chunk)
+            .map_err(|e| PhnxError::io(format!("Error writing {}", filename), e))?;
+
+        remaining_length -= chunk_size as u64;
+        bar.update(length - remaining_length, length);
     }
-    eprint!(" \r");
+    bar.clear();
 
     let crc32c_before_val = crc32c_before.finalize();
     let crc32c_after_val = crc32c_after.finalize();
+    let report = Report {
+        length: plaintext_length,
+        ..Report::default()
+    };
 
-    if golay_encode {
-        let mut suffix = [0u64; 3];
-        suffix[0] = ((crc32c_before_val as u64) << 32) | (crc32c_before_val as u64);
-        suffix[1] = nonce;
-        suffix[2] = length as u64;
-
-        // Encrypt suffix with nonce=-1, counter=-1, -2
-        let nonce_ctr_m1 = [0xffffffffffffffffu64, 0xffffffffffffffffu64];
-        let nonce_ctr_m2 = [0xffffffffffffffffu64, 0xfffffffffffffffeu64];
-        let gamma1 = speck::speck_encrypt(&nonce_ctr_m1, schedule);
-        let gamma2 = speck::speck_encrypt(&nonce_ctr_m2, schedule);
-        suffix[0] ^= gamma1[0];
-        suffix[1] ^= gamma1[1];
-        suffix[2] ^= gamma2[0];
-
-        let mut suffix_bytes = [0u8; 24];
-        suffix_bytes[0..8].copy_from_slice(&suffix[0].to_le_bytes());
-        suffix_bytes[8..16].copy_from_slice(&suffix[1].to_le_bytes());
-        suffix_bytes[16..24].copy_from_slice(&suffix[2].to_le_bytes());
-
-        let ret = golay_encode_and_write(&suffix_bytes, 24, &mut slices_w, &mut gc);
-        if ret != PHNX_OK {
-            return ret;
-        }
-
-        // Close slices (drop them)
-        for i in 0..8 {
-            slices_w[i] = None;
-        }
-
-        return PHNX_OK;
-    } else if append_suffix {
-        let mut suffix = [0u64; 2];
-        suffix[0] = ((crc32c_before_val as u64) << 32) | (crc32c_before_val as u64);
-        suffix[1] = nonce;
-
-        let all_ones = [0xffffffffffffffffu64, 0xffffffffffffffffu64];
-        let gamma = speck::speck_encrypt(&all_ones, schedule);
-        suffix[0] ^= gamma[0];
-        suffix[1] ^= gamma[1];
-
-        let file_ref = f.as_mut().unwrap();
-        let mut suffix_bytes = [0u8; 16];
-        suffix_bytes[0..8].copy_from_slice(&suffix[0].to_le_bytes());
-        suffix_bytes[8..16].copy_from_slice(&suffix[1].to_le_bytes());
-        if file_ref.write_all(&suffix_bytes).is_err() {
-            eprintln!("\nError writing suffix");
-            return PHNX_IO_ERROR;
-        }
-        drop(f);
-        let new_filename = format!("{}.encrypted", filename);
-        if fs::rename(filename, &new_filename).is_err() {
-            eprintln!("Error renaming {} to {}", filename, new_filename);
-            return PHNX_IO_ERROR;
-        }
-        return PHNX_OK;
-    }
-
-    // Close main file
-    drop(f);
-
-    if check_checksum {
-        let checksum_in = [
-            ((crc32c_before_val as u64) << 32) | (crc32c_after_val as u64),
-            length as u64,
-        ];
-        let checksum_out = speck::speck_encrypt(&checksum_in, schedule);
-        let checksum = checksum_out[0] as u32;
-
-        if checksum != expected_checksum {
-            eprintln!(
-                "Checksum mismatch: expected 0x{:x}, got 0x{:x}",
-                expected_checksum, checksum
-            );
-            return PHNX_FORMAT_ERROR;
-        } else {
-            let new_filename = &filename[..hex_suffix_rename_end.unwrap_or(0)];
-            if fs::rename(filename, new_filename).is_err() {
-                eprintln!("Error renaming {} to {}", filename, new_filename);
-                return PHNX_IO_ERROR;
-            }
-            return PHNX_OK;
-        }
-    }
+    match mode {
+        Legacy::Encrypt => {
+            let mut suffix = [0u64; 2];
+            suffix[0] = ((crc32c_before_val as u64) << 32) | (crc32c_before_val as u64);
+            suffix[1] = nonce;
 
-    if check_crc32c {
-        if expected_crc32c != crc32c_after_val {
-            eprintln!(
-                "CRC32C mismatch: expected 0x{:x}, got 0x{:x}",
-                expected_crc32c, crc32c_after_val
-            );
-            return PHNX_FORMAT_ERROR;
-        } else if !golay_decode {
-            // Remove .encrypted suffix from filename
-            let new_filename = &filename[..filename.len() - 10]; // strip ".encrypted"
-            if fs::rename(filename, new_filename).is_err() {
-                eprintln!("Error renaming {} to {}", filename, new_filename);
-                return PHNX_IO_ERROR;
-            }
-            // Truncate to remove the 16-byte suffix
-            let trunc_file = OpenOptions::new().write(true).open(new_filename);
-            match trunc_file {
-                Ok(f) => {
-                    if f.set_len((length - 16) as u64).is_err() {
-                        eprintln!("Error truncating {}", new_filename);
-                        return PHNX_IO_ERROR;
-                    }
-                }
-                Err(_) => {
-                    eprintln!("Error truncating {}", new_filename);
-                    return PHNX_IO_ERROR;
-                }
+            let all_ones = [0xffffffffffffffffu64, 0xffffffffffffffffu64];
+            let gamma = speck::speck_encrypt(&all_ones, schedule);
+            suffix[0] ^= gamma[0];
+            suffix[1] ^= gamma[1];
+
+            let mut suffix_bytes = [0u8; 16];
+            suffix_bytes[0..8].copy_from_slice(&suffix[0].to_le_bytes());
+            suffix_bytes[8..16].copy_from_slice(&suffix[1].to_le_bytes());
+            f.write_all(&suffix_bytes)
+                .map_err(|e| PhnxError::io("Error writing suffix", e))?;
+            drop(f);
+
+            rename(filename, &new_filename)?;
+        }
+        Legacy::DecryptChecksum(expected_checksum, _) => {
+            drop(f);
+            let checksum_in = [
+                ((crc32c_before_val as u64) << 32) | (crc32c_after_val as u64),
+                length,
+            ];
+            let checksum_out = speck::speck_encrypt(&checksum_in, schedule);
+            let checksum = checksum_out[0] as u32;
+
+            if checksum != expected_checksum {
+                return Err(PhnxError::Format(format!(
+                    "Checksum mismatch: expected 0x{:x}, got 0x{:x}",
+                    expected_checksum, checksum
+                )));
             }
+            rename(filename, &new_filename)?;
         }
-    }
+        Legacy::DecryptCrc32c => {
+            drop(f);
+            if expected_crc32c != crc32c_after_val {
+                return Err(PhnxError::Format(format!(
+                    "CRC32C mismatch: expected 0x{:x}, got 0x{:x}",
+                    expected_crc32c, crc32c_after_val
+                )));
+            }
 
-    if golay_decode {
-        if gc.corrected_codewords != 0 || gc.uncorrectable_codewords != 0 {
-            eprintln!(
-                "Processed {} Golay codewords, corrected {}, {} uncorrectable",
-                gc.processed_codewords, gc.corrected_codewords, gc.uncorrectable_codewords
-            );
-        }
-        if gc.uncorrectable_codewords != 0 {
-            return PHNX_UNCORRECTABLE_ERROR;
+            // Remove .encrypted suffix from filename, truncate to remove the 16-byte suffix
+            rename(filename, &new_filename)?;
+            OpenOptions::new()
+                .write(true)
+                .open(&new_filename)
+                .and_then(|f| f.set_len(length - 16))
+                .map_err(|e| PhnxError::io(format!("Error truncating {}", new_filename), e))?;
         }
     }
 
-    PHNX_OK
+    Ok(report)
+}
+
+fn rename(from: &str, to: &str) -> Result<(), PhnxError> {
+    fs::rename(from, to)
+        .map_err(|e| PhnxError::io(format!("Error renaming {} to {}", from, to), e))
 }
@@ -0,0 +1,82 @@
+//! Random bytes from the operating system, for nonces, salts, and set ids. There is no fallback
+//! to time or CPU instructions: a nonce that repeats gives away the XOR of two plaintexts, so
+//! encoding fails instead.
+
+use crate::error::PhnxError;
+
+/// Fills buf from getrandom(2) where the kernel has it, /dev/urandom otherwise
+pub(crate) fn fill(buf: &mut [u8]) -> Result<(), PhnxError> {
+    #[cfg(target_os = "linux")]
+    match getrandom(buf) {
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {}
+        result => return result.map_err(|e| PhnxError::io("Cannot get random bytes", e)),
+    }
+
+    #[cfg(unix)]
+    {
+        use std::fs::File;
+        use std::io::Read;
+
+        File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(buf))
+            .map_err(|e| PhnxError::io("Cannot read random bytes from /dev/urandom", e))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = buf;
+        Err(PhnxError::io(
+            "No operating system random number generator",
+            std::io::Error::from(std::io::ErrorKind::Unsupported),
+        ))
+    }
+}
+
+pub(crate) fn random_u64() -> Result<u64, PhnxError> {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Number of the getrandom system call, called through syscall(2) so that C libraries older
+/// than the wrapper still link. Unsupported on other architectures, which read /dev/urandom.
+#[cfg(target_os = "linux")]
+const SYS_GETRANDOM: Option<std::ffi::c_long> = if cfg!(target_arch = "x86_64") {
+    Some(318)
+} else if cfg!(target_arch = "x86") {
+    Some(355)
+} else if cfg!(target_arch = "arm") {
+    Some(384)
+} else if cfg!(any(target_arch = "aarch64", target_arch = "riscv64")) {
+    Some(278)
+} else {
+    None
+};
+
+/// Blocks until the kernel pool is initialized, never returns less than asked for. Fails
+/// with ErrorKind::Unsupported if the kernel or architecture has no getrandom.
+#[cfg(target_os = "linux")]
+fn getrandom(mut buf: &mut [u8]) -> std::io::Result<()> {
+    use std::ffi::c_long;
+
+    extern "C" {
+        fn syscall(number: c_long, ...) -> c_long;
+    }
+
+    let Some(number) = SYS_GETRANDOM else {
+        return Err(std::io::ErrorKind::Unsupported.into());
+    };
+    while !buf.is_empty() {
+        // Requests over 32 MiB may be cut short, interrupted ones are retried
+        let n = unsafe { syscall(number, buf.as_mut_ptr(), buf.len(), 0u32) };
+        if n < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        buf = &mut buf[n as usize..];
+    }
+    Ok(())
+}
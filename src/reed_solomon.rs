@@ -0,0 +1,329 @@
+//! Reed-Solomon code over GF(256).
+//!
+//! Every codeword has one byte per slice: data_slices bytes of data followed by parity_slices
+//! bytes of parity, so any parity_slices lost slices can be filled in. Lost slices are known
+//! erasures; bytes of the others may also be wrong, as long as twice the number of wrong
+//! bytes plus the number of erasures does not exceed parity_slices. Codewords are those of the
+//! generator polynomial with roots 1, a, ..., a^(parity_slices - 1), where a is 2 in the field
+//! reduced by x^8 + x^4 + x^3 + x^2 + 1, and byte i of n is the coefficient of x^(n - 1 - i).
+
+use crate::golay::GolayStats;
+
+/// Longest codeword the field allows
+pub const MAX_CODEWORD: usize = 255;
+
+/// Polynomial that reduces products, without the x^8 term
+const FIELD_POLY: u16 = 0x11d;
+
+/// Powers of a, twice over so that the sum of two logarithms needs no reduction
+const EXP: [u8; 512] = {
+    let mut exp = [0u8; 512];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= FIELD_POLY;
+        }
+        i += 1;
+    }
+    exp
+};
+
+/// Logarithms to base a, LOG[0] is unused
+const LOG: [u8; 256] = {
+    let mut log = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        log[EXP[i] as usize] = i as u8;
+        i += 1;
+    }
+    log
+};
+
+#[inline]
+fn mul(x: u8, y: u8) -> u8 {
+    if x == 0 || y == 0 {
+        0
+    } else {
+        EXP[LOG[x as usize] as usize + LOG[y as usize] as usize]
+    }
+}
+
+#[inline]
+fn div(x: u8, y: u8) -> u8 {
+    if x == 0 {
+        0
+    } else {
+        EXP[LOG[x as usize] as usize + 255 - LOG[y as usize] as usize]
+    }
+}
+
+/// a^e for any e >= 0
+#[inline]
+fn pow(e: usize) -> u8 {
+    EXP[e % 255]
+}
+
+/// Value at x of a polynomial with coefficients in ascending order of powers
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+pub struct ReedSolomon {
+    data_slices: usize,
+    parity_slices: usize,
+    /// Generator polynomial without its leading 1, in descending order of powers
+    generator: Vec<u8>,
+    pub stats: GolayStats,
+}
+
+impl ReedSolomon {
+    /// Code with data_slices + parity_slices bytes per codeword, which must be at most
+    /// MAX_CODEWORD, with at least one of each
+    pub fn new(data_slices: usize, parity_slices: usize) -> Self {
+        assert!(data_slices >= 1 && parity_slices >= 1);
+        assert!(data_slices + parity_slices <= MAX_CODEWORD);
+
+        // Product of (x - a^j), in descending order of powers
+        let mut generator = vec![1u8];
+        for j in 0..parity_slices {
+            let root = pow(j);
+            let mut next = generator.clone();
+            next.push(0);
+            for (i, &c) in generator.iter().enumerate() {
+                next[i + 1] ^= mul(c, root);
+            }
+            generator = next;
+        }
+        generator.remove(0);
+
+        ReedSolomon {
+            data_slices,
+            parity_slices,
+            generator,
+            stats: GolayStats::default(),
+        }
+    }
+
+    pub fn data_slices(&self) -> usize {
+        self.data_slices
+    }
+
+    fn codeword_len(&self) -> usize {
+        self.data_slices + self.parity_slices
+    }
+
+    /// Takes data_slices bytes, returns them followed by parity_slices bytes of parity
+    pub fn encode(&self, data: &[u8], codeword: &mut [u8]) {
+        let (head, parity) = codeword.split_at_mut(self.data_slices);
+        head.copy_from_slice(&data[..self.data_slices]);
+        parity.fill(0);
+        // Remainder of data * x^parity_slices divided by the generator
+        for &d in data[..self.data_slices].iter() {
+            let feedback = d ^ parity[0];
+            parity.copy_within(1.., 0);
+            parity[self.parity_slices - 1] = 0;
+            if feedback != 0 {
+                for (p, &g) in parity.iter_mut().zip(&self.generator) {
+                    *p ^= mul(g, feedback);
+                }
+            }
+        }
+    }
+
+    /// Locator of byte i, the power of a at which its coefficient sits
+    fn locator(&self, i: usize) -> u8 {
+        pow(self.codeword_len() - 1 - i)
+    }
+
+    /// Syndromes of a codeword, all zero if it is valid
+    fn syndromes(&self, codeword: &[u8], syndromes: &mut [u8]) {
+        for (j, s) in syndromes.iter_mut().enumerate() {
+            let root = pow(j);
+            *s = codeword.iter().fold(0, |acc, &c| mul(acc, root) ^ c);
+        }
+    }
+
+    /// Corrects a codeword in place, ignoring bytes marked as erased and filling them in.
+    /// Sets the bits corrected in bytes that were not erased in errors. Returns false and
+    /// leaves the codeword as it was if it has more errors than parity can correct.
+    pub fn decode(&mut self, codeword: &mut [u8], erased: &[bool], errors: &mut [u8]) -> bool {
+        self.stats.processed_codewords += 1;
+        let n = self.codeword_len();
+        let m = self.parity_slices;
+        errors[..n].fill(0);
+
+        let mut received = codeword[..n].to_vec();
+        for i in 0..n {
+            if erased[i] {
+                received[i] = 0;
+            }
+        }
+        let mut syndromes = vec![0u8; m];
+        self.syndromes(&received, &mut syndromes);
+        let erasure_count = erased[..n].iter().filter(|&&e| e).count();
+        if erasure_count > m {
+            self.stats.uncorrectable_codewords += 1;
+            return false;
+        }
+        if syndromes.iter().all(|&s| s == 0) {
+            codeword[..n].copy_from_slice(&received);
+            return true;
+        }
+
+        // Berlekamp-Massey started from the erasure locator finds the locator of errors and
+        // erasures together
+        let mut lambda = vec![1u8];
+        for (i, &e) in erased.iter().enumerate().take(n) {
+            if e {
+                let x = self.locator(i);
+                lambda.push(0);
+                for k in (1..lambda.len()).rev() {
+                    lambda[k] ^= mul(lambda[k - 1], x);
+                }
+            }
+        }
+        let mut previous = lambda.clone();
+        let mut length = erasure_count;
+        for r in erasure_count..m {
+            let mut discrepancy = 0;
+            for (i, &l) in lambda.iter().enumerate().take(r + 1) {
+                discrepancy ^= mul(l, syndromes[r - i]);
+            }
+            previous.insert(0, 0);
+            if discrepancy != 0 {
+                let mut next = lambda.clone();
+                next.resize(next.len().max(previous.len()), 0);
+                for (k, &p) in previous.iter().enumerate() {
+                    next[k] ^= mul(discrepancy, p);
+                }
+                if 2 * length <= r + erasure_count {
+                    previous = lambda.iter().map(|&l| div(l, discrepancy)).collect();
+                    length = r + 1 + erasure_count - length;
+                }
+                lambda = next;
+            }
+        }
+        while lambda.last() == Some(&0) {
+            lambda.pop();
+        }
+        let error_count = length - erasure_count;
+        if lambda.len() != length + 1 || 2 * error_count + erasure_count > m {
+            self.stats.uncorrectable_codewords += 1;
+            return false;
+        }
+
+        // Roots of the locator are inverse locators of the bytes to correct; Forney's
+        // formula gives their values from the evaluator, syndromes times locator mod x^m
+        let mut evaluator = vec![0u8; m];
+        for (i, &l) in lambda.iter().enumerate() {
+            for j in 0..m - i.min(m) {
+                evaluator[i + j] ^= mul(l, syndromes[j]);
+            }
+        }
+        let derivative: Vec<u8> = lambda
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(i, &l)| if i % 2 == 1 { l } else { 0 })
+            .collect();
+        let mut corrected = received.clone();
+        let mut roots = 0;
+        for i in 0..n {
+            let x = self.locator(i);
+            let x_inverse = div(1, x);
+            if eval(&lambda, x_inverse) != 0 {
+                continue;
+            }
+            roots += 1;
+            let value = mul(x, div(eval(&evaluator, x_inverse), eval(&derivative, x_inverse)));
+            corrected[i] ^= value;
+            if !erased[i] {
+                errors[i] = value;
+            }
+        }
+
+        // Too many errors can still yield a locator, whose roots then miss the codeword
+        self.syndromes(&corrected, &mut syndromes);
+        if roots != length || syndromes.iter().any(|&s| s != 0) {
+            errors[..n].fill(0);
+            self.stats.uncorrectable_codewords += 1;
+            return false;
+        }
+        if error_count != 0 {
+            self.stats.corrected_codewords += 1;
+        }
+        codeword[..n].copy_from_slice(&corrected);
+        true
+    }
+}
+
+pub fn self_test() -> bool {
+    // Simple LCG for deterministic testing, same as the Golay self-test
+    let mut rng_state: u32 = 12345;
+    let mut next_rand = || -> u32 {
+        rng_state = rng_state.wrapping_mul(1103515245).wrapping_add(12345);
+        (rng_state >> 16) & 0x7fff
+    };
+
+    for (data_slices, parity_slices) in [(10, 4), (4, 2), (6, 6), (1, 1), (20, 4), (200, 55)] {
+        let mut rs = ReedSolomon::new(data_slices, parity_slices);
+        let n = data_slices + parity_slices;
+        for _ in 0..1000 {
+            let data: Vec<u8> = (0..data_slices).map(|_| next_rand() as u8).collect();
+            let mut codeword = vec![0u8; n];
+            rs.encode(&data, &mut codeword);
+
+            // Erasures filled with garbage, plus as many errors elsewhere as parity allows
+            let erasure_count = next_rand() as usize % (parity_slices + 1);
+            let mut erased = vec![false; n];
+            while erased.iter().filter(|&&e| e).count() < erasure_count {
+                erased[next_rand() as usize % n] = true;
+            }
+            let error_count = (parity_slices - erasure_count) / 2;
+            let mut expected_errors = vec![0u8; n];
+            let mut received = codeword.clone();
+            while expected_errors.iter().filter(|&&e| e != 0).count() < error_count {
+                let i = next_rand() as usize % n;
+                if !erased[i] && expected_errors[i] == 0 {
+                    expected_errors[i] = (next_rand() % 255 + 1) as u8;
+                    received[i] ^= expected_errors[i];
+                }
+            }
+            for i in 0..n {
+                if erased[i] {
+                    received[i] = next_rand() as u8;
+                }
+            }
+
+            let mut errors = vec![0u8; n];
+            if !rs.decode(&mut received, &erased, &mut errors)
+                || received != codeword
+                || errors != expected_errors
+            {
+                eprintln!("ReedSolomon self-test failed");
+                eprintln!(
+                    "RS({}, {}) with {} erasures and {} errors",
+                    n, data_slices, erasure_count, error_count
+                );
+                return false;
+            }
+        }
+    }
+
+    // More erasures than parity bytes cannot be filled in
+    let mut rs = ReedSolomon::new(10, 4);
+    let mut codeword = [0u8; 14];
+    rs.encode(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], &mut codeword);
+    let mut erased = [false; 14];
+    erased[..5].fill(true);
+    if rs.decode(&mut codeword, &erased, &mut [0u8; 14]) {
+        eprintln!("ReedSolomon self-test failed, five erasures filled in with four parity bytes");
+        return false;
+    }
+
+    true
+}
@@ -0,0 +1,1981 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::codec::{Codec, CodecParams, DEFAULT_SLICE_COUNT};
+use crate::crc32c::Crc32c;
+use crate::error::PhnxError;
+use crate::golay::{GolayCode, GolayStats};
+use crate::kdf::KdfParams;
+use crate::key::Key;
+use crate::keyslot::{self, KeySlot, Wrapping, KEY_SLOT_SIZE};
+use crate::metadata::FileMetadata;
+use crate::pipeline;
+use crate::poly1305::{tags_equal, Poly1305};
+use crate::random;
+use crate::speck;
+
+/// Bytes processed per iteration, a multiple of both the 64 byte keystream group and the
+/// 12 byte Golay block. This is also the unit of authentication, so changing it changes the
+/// format.
+const CHUNK_SIZE: usize = 16 * 4 * 12 * 100;
+
+/// Start of the plaintext block that precedes the suffix in files with a derived key
+const PARAMS_MAGIC: &[u8; 4] = b"PHNX";
+
+/// Format version written in the parameters block and slice headers. Version 1 files have
+/// neither, nor tags, and use the password directly as the key.
+const FORMAT_VERSION: u8 = 2;
+
+/// Bytes at the start of every slice after version 1: a 48 byte header, Golay encoded within
+/// that slice alone as 32 codewords of 3 bytes
+pub const SLICE_HEADER_SIZE: u64 = 96;
+
+/// Fails if more slices are missing than the set can lose
+fn check_missing<R>(slices: &[Option<R>], params: &CodecParams) -> Result<(), PhnxError> {
+    let missing = slices.iter().filter(|s| s.is_none()).count();
+    let max_missing = params.max_missing();
+    if missing > max_missing {
+        return Err(PhnxError::Uncorrectable(format!(
+            "{} of {} slices are missing, not enough to recover from more than {}",
+            missing,
+            slices.len(),
+            max_missing
+        )));
+    }
+    Ok(())
+}
+
+/// Counters of chunk MAC keys have the top bit set, two keystream blocks per chunk
+const MAC_COUNTER: u64 = 1 << 63;
+
+/// Counters of the trailer MAC key, out of reach of both chunk MAC keys and the suffix
+const TRAILER_MAC_COUNTER: u64 = u64::MAX - 3;
+
+/// Keystream counter of the first metadata block, far past any chunk and below MAC keys
+const METADATA_COUNTER: u64 = 1 << 62;
+
+/// Errors closer than this many bytes to each other are reported as one cluster
+const CLUSTER_GAP: u64 = 4096;
+
+/// Clusters kept per slice, the last one absorbs everything past the limit
+const MAX_CLUSTERS: usize = 64;
+
+/// Bit errors corrected in one slice, and where they were
+#[derive(Debug, Clone, Default)]
+pub struct SliceErrors {
+    pub bits: u64,
+    pub clusters: Vec<ErrorCluster>,
+}
+
+/// Range of byte offsets within a slice file where bit errors were corrected
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorCluster {
+    pub first_offset: u64,
+    pub last_offset: u64,
+    pub bits: u64,
+}
+
+impl SliceErrors {
+    /// Adds errors found in another part of the same slice
+    pub fn merge(&mut self, other: &SliceErrors) {
+        self.bits += other.bits;
+        self.clusters.extend_from_slice(&other.clusters);
+        self.clusters.sort_by_key(|c| c.first_offset);
+
+        let mut merged: Vec<ErrorCluster> = Vec::with_capacity(self.clusters.len());
+        for c in self.clusters.drain(..) {
+            let too_many = merged.len() >= MAX_CLUSTERS;
+            match merged.last_mut() {
+                Some(m) if too_many || c.first_offset <= m.last_offset + CLUSTER_GAP =>
+                {
+                    m.last_offset = m.last_offset.max(c.last_offset);
+                    m.bits += c.bits;
+                }
+                _ => merged.push(c),
+            }
+        }
+        self.clusters = merged;
+    }
+
+    fn record(&mut self, offset: u64, bits: u64) {
+        self.bits += bits;
+        let too_many = self.clusters.len() >= MAX_CLUSTERS;
+        match self.clusters.last_mut() {
+            Some(c)
+                if too_many
+                    || (offset + CLUSTER_GAP >= c.first_offset
+                        && offset <= c.last_offset + CLUSTER_GAP) =>
+            {
+                c.first_offset = c.first_offset.min(offset);
+                c.last_offset = c.last_offset.max(offset);
+                c.bits += bits;
+            }
+            _ => self.clusters.push(ErrorCluster {
+                first_offset: offset,
+                last_offset: offset,
+                bits,
+            }),
+        }
+    }
+}
+
+/// Attributes bits corrected in a block read at given offset to the slices they came from
+fn record_errors(
+    slice_errors: &mut [SliceErrors],
+    share: usize,
+    slice_offset: u64,
+    errors: &[u8],
+) {
+    for (j, e) in errors.iter().enumerate() {
+        let bits = e.count_ones();
+        if bits != 0 {
+            slice_errors[j / share].record(slice_offset + (j % share) as u64, bits as u64);
+        }
+    }
+}
+
+/// Adds a range to a sorted list of ranges, merging it with the last one if they touch
+fn add_damage<T: Ord + Copy>(damaged: &mut Vec<Range<T>>, range: Range<T>) {
+    match damaged.last_mut() {
+        Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+        _ => damaged.push(range),
+    }
+}
+
+/// Statistics of an encode or decode
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Plaintext length in bytes, of the range if only one was decoded
+    pub length: u64,
+    /// CRC32C of the plaintext, as written or as decoded
+    pub crc32c: u32,
+    pub stats: GolayStats,
+    /// Bit errors corrected in each slice, starting with A
+    pub slice_errors: Vec<SliceErrors>,
+    /// Plaintext byte ranges hit by uncorrectable codewords, sorted and non-overlapping
+    pub damaged: Vec<Range<u64>>,
+    /// Chunks whose MAC does not match although all their codewords were corrected
+    pub unauthenticated: Vec<Range<u64>>,
+    /// Attributes of the original file, if the encoder was given them
+    pub metadata: Option<FileMetadata>,
+}
+
+/// What unrecoverable plaintext bytes are replaced with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DamageFill {
+    #[default]
+    Zeroes,
+    /// Repeated DAMAGE_MARKER, easy to spot in text and hex dumps
+    Marker,
+}
+
+/// Pattern written over damaged bytes with DamageFill::Marker, aligned to file offsets
+pub const DAMAGE_MARKER: &[u8; 8] = b"DAMAGED!";
+
+impl DamageFill {
+    /// Overwrites buffer, which starts at given plaintext offset
+    fn apply(self, buffer: &mut [u8], offset: u64) {
+        match self {
+            DamageFill::Zeroes => buffer.fill(0),
+            DamageFill::Marker => {
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = DAMAGE_MARKER[((offset + i as u64) % 8) as usize];
+                }
+            }
+        }
+    }
+}
+
+pub fn slice_letter(i: usize) -> char {
+    (b'A' + i as u8) as char
+}
+
+fn missing<R>(slices: &[Option<R>]) -> Vec<bool> {
+    slices.iter().map(Option::is_none).collect()
+}
+
+/// Reads the share of an encoded block from each available slice, bytes of missing ones are 0
+fn read_block<R: Read>(
+    slices: &mut [Option<R>],
+    share: usize,
+    block: &mut [u8],
+) -> Result<(), PhnxError> {
+    block.fill(0);
+    for (i, slice) in slices.iter_mut().enumerate() {
+        if let Some(f) = slice {
+            let base = i * share;
+            if let Err(e) = f.read_exact(&mut block[base..base + share]) {
+                return Err(PhnxError::io(
+                    format!("Error reading from slice {}", slice_letter(i)),
+                    e,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_block<W: Write>(
+    slices: &mut [Option<W>],
+    share: usize,
+    block: &[u8],
+) -> Result<(), PhnxError> {
+    for (i, slice) in slices.iter_mut().enumerate() {
+        let base = i * share;
+        if let Some(f) = slice {
+            if let Err(e) = f.write_all(&block[base..base + share]) {
+                return Err(PhnxError::io(
+                    format!("Error writing slice {}", slice_letter(i)),
+                    e,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn flush_slices<W: Write>(slices: &mut [Option<W>]) -> Result<(), PhnxError> {
+    for (i, slice) in slices.iter_mut().enumerate() {
+        if let Some(f) = slice {
+            f.flush().map_err(|e| {
+                PhnxError::io(format!("Error writing slice {}", slice_letter(i)), e)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads and decodes enough blocks for bytes_to_read bytes, starting at slice_offset
+/// within the slices. Returns ranges of the buffer that hold uncorrectable blocks.
+fn read_and_decode<R: Read>(
+    buffer: &mut [u8],
+    bytes_to_read: usize,
+    slices: &mut [Option<R>],
+    codec: &mut dyn Codec,
+    slice_errors: &mut [SliceErrors],
+    mut slice_offset: u64,
+) -> Result<Vec<Range<usize>>, PhnxError> {
+    let missing = missing(slices);
+    let block_size = codec.block_size();
+    let share = codec.share();
+    let mut encoded = vec![0u8; share * slices.len()];
+    let mut errors = vec![0u8; encoded.len()];
+    let mut decoded = vec![0u8; block_size];
+    let mut damaged = Vec::new();
+    let mut block_offset = 0;
+    while block_offset < bytes_to_read {
+        read_block(slices, share, &mut encoded)?;
+        let correctable = codec.decode_block(&encoded, &missing, &mut decoded, &mut errors);
+        record_errors(slice_errors, share, slice_offset, &errors);
+        slice_offset += share as u64;
+
+        // Every byte of the block depends on all of its codewords, so a lost codeword
+        // damages the whole block
+        let end = std::cmp::min(block_offset + block_size, buffer.len());
+        if !correctable {
+            add_damage(&mut damaged, block_offset..end);
+        }
+        buffer[block_offset..end].copy_from_slice(&decoded[..end - block_offset]);
+        block_offset += block_size;
+    }
+    Ok(damaged)
+}
+
+fn encode_and_write<W: Write>(
+    data: &[u8],
+    data_size: usize,
+    slices: &mut [Option<W>],
+    codec: &dyn Codec,
+) -> Result<(), PhnxError> {
+    let block_size = codec.block_size();
+    let share = codec.share();
+    let mut encoded = vec![0u8; share * slices.len()];
+    let mut block_offset = 0;
+    while block_offset < data_size {
+        // Pad with zeroes
+        let mut block = vec![0u8; block_size];
+        let copy_size = std::cmp::min(block_size, data_size - block_offset);
+        block[..copy_size].copy_from_slice(&data[block_offset..block_offset + copy_size]);
+
+        codec.encode_block(&block, &mut encoded);
+        write_block(slices, share, &encoded)?;
+        block_offset += block_size;
+    }
+    Ok(())
+}
+
+/// Keystream position of a chunk: each one takes CHUNK_SIZE / 16 counter values, so chunks
+/// can be encrypted in any order
+fn chunk_counter(nonce: u64, index: u64) -> [u64; 8] {
+    let counter = index * (CHUNK_SIZE / 16) as u64;
+    [nonce, nonce, nonce, nonce, counter, counter + 1, counter + 2, counter + 3]
+}
+
+/// Encrypts a chunk, tags it, and encodes both, returning what goes into each slice
+fn encode_chunk(
+    mut chunk: Vec<u8>,
+    index: u64,
+    nonce: u64,
+    schedule: &[u64; 34],
+    params: &CodecParams,
+) -> Result<Vec<Vec<u8>>, PhnxError> {
+    speck::speck_ctr(&mut chunk, &mut chunk_counter(nonce, index), schedule);
+    let tag = chunk_tag(schedule, nonce, index, &chunk);
+
+    let part_size = params.slice_bytes(chunk.len()) + params.slice_bytes(24);
+    let mut parts: Vec<_> = (0..params.slice_count())
+        .map(|_| Some(Vec::with_capacity(part_size)))
+        .collect();
+    let codec = params.codec();
+    encode_and_write(&chunk, chunk.len(), &mut parts, codec.as_ref())?;
+    encode_and_write(&tag_block(&tag), 24, &mut parts, codec.as_ref())?;
+    Ok(parts.into_iter().map(Option::unwrap).collect())
+}
+
+/// Appends to each slice its part of a chunk
+fn write_slice_parts<W: Write>(
+    slices: &mut [Option<W>],
+    parts: &[Vec<u8>],
+) -> Result<(), PhnxError> {
+    for i in 0..slices.len() {
+        if let Some(ref mut f) = slices[i] {
+            f.write_all(&parts[i])
+                .map_err(|e| PhnxError::io(format!("Error writing slice {}", slice_letter(i)), e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads part_size bytes from each available slice
+fn read_slice_parts<R: Read>(
+    slices: &mut [Option<R>],
+    part_size: usize,
+) -> Result<Vec<Option<Vec<u8>>>, PhnxError> {
+    let mut parts = vec![None; slices.len()];
+    for i in 0..slices.len() {
+        if let Some(ref mut f) = slices[i] {
+            let mut part = vec![0u8; part_size];
+            f.read_exact(&mut part).map_err(|e| {
+                PhnxError::io(format!("Error reading from slice {}", slice_letter(i)), e)
+            })?;
+            parts[i] = Some(part);
+        }
+    }
+    Ok(parts)
+}
+
+/// Slice parts of one chunk and of its tag, as read by the decoder
+struct ChunkParts {
+    index: u64,
+    size: usize,
+    slice_offset: u64,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+/// One chunk corrected and decrypted, with what went wrong in it
+struct DecodedChunk {
+    index: u64,
+    plaintext: Vec<u8>,
+    /// Ranges of plaintext that could not be recovered
+    damaged: Vec<Range<usize>>,
+    /// Damaged, or failed authentication
+    failed: bool,
+    /// Failed authentication although error correction found nothing wrong
+    forged: bool,
+    stats: GolayStats,
+    slice_errors: Vec<SliceErrors>,
+}
+
+/// Decodes a chunk and its tag, checks the tag if there is one, and decrypts the chunk
+fn decode_chunk(
+    chunk: ChunkParts,
+    nonce: u64,
+    schedule: &[u64; 34],
+    params: &CodecParams,
+    authenticated: bool,
+) -> Result<DecodedChunk, PhnxError> {
+    let mut parts: Vec<_> = chunk
+        .parts
+        .into_iter()
+        .map(|part| part.map(io::Cursor::new))
+        .collect();
+    let tag_offset = chunk.slice_offset + params.slice_bytes(chunk.size) as u64;
+    let mut codec = params.codec();
+    let mut slice_errors = vec![SliceErrors::default(); parts.len()];
+    let mut plaintext = vec![0u8; chunk.size];
+    let mut damaged = read_and_decode(
+        &mut plaintext,
+        chunk.size,
+        &mut parts,
+        codec.as_mut(),
+        &mut slice_errors,
+        chunk.slice_offset,
+    )?;
+
+    let mut failed = !damaged.is_empty();
+    let mut forged = false;
+    if authenticated {
+        let mut block = [0u8; 24];
+        let tag_damaged = read_and_decode(
+            &mut block,
+            24,
+            &mut parts,
+            codec.as_mut(),
+            &mut slice_errors,
+            tag_offset,
+        )?;
+
+        let tag = chunk_tag(schedule, nonce, chunk.index, &plaintext);
+        if tags_equal(&tag, block_tag(&block)) {
+            damaged.clear();
+            failed = false;
+        } else {
+            failed = true;
+            if damaged.is_empty() {
+                // Lost tag cannot vouch for the chunk, otherwise it was modified
+                if tag_damaged.is_empty() {
+                    forged = true;
+                } else {
+                    damaged.push(0..chunk.size);
+                }
+            }
+        }
+    }
+    speck::speck_ctr(&mut plaintext, &mut chunk_counter(nonce, chunk.index), schedule);
+
+    Ok(DecodedChunk {
+        index: chunk.index,
+        plaintext,
+        damaged,
+        failed,
+        forged,
+        stats: codec.stats().clone(),
+        slice_errors,
+    })
+}
+
+/// Health of a slice set, found by reading and correcting every codeword
+#[derive(Debug, Clone, Default)]
+pub struct SliceHealth {
+    pub stats: GolayStats,
+    /// Bit errors corrected in each slice, starting with A
+    pub slice_errors: Vec<SliceErrors>,
+    /// Slices that were not available
+    pub missing: Vec<bool>,
+    /// Slices whose header is damaged beyond repair or does not match the set
+    pub header_damaged: Vec<bool>,
+    /// Byte ranges of the error-corrected stream hit by uncorrectable codewords. After
+    /// version 1 the stream has a 24 byte tag after every chunk, so these are not plaintext
+    /// offsets.
+    pub damaged: Vec<Range<u64>>,
+}
+
+/// Reads every codeword of the slices, corrects it, and writes it re-encoded to those
+/// outputs that are not None. Works on ciphertext, so no key is needed. Slices after
+/// version 1 start with a header, which is checked against the one given and rewritten.
+/// There must be as many outputs as slices.
+fn scan_slices<R: Read, W: Write>(
+    slices: &mut [Option<R>],
+    slice_length: u64,
+    header: Option<&SliceHeader>,
+    outputs: &mut [Option<W>],
+    mut progress: Option<&mut dyn FnMut(u64, u64)>,
+) -> Result<SliceHealth, PhnxError> {
+    let slice_count = slices.len();
+    let params = header.map_or_else(CodecParams::default, SliceHeader::codec_params);
+    params.check()?;
+    match header {
+        Some(h) if h.slice_count as usize != slice_count => {
+            return Err(PhnxError::Format(format!(
+                "Header is for {} slices, got {}",
+                h.slice_count, slice_count
+            )));
+        }
+        None if slice_count != DEFAULT_SLICE_COUNT => {
+            return Err(PhnxError::Format(format!(
+                "Slices without headers come in sets of {}, got {}",
+                DEFAULT_SLICE_COUNT, slice_count
+            )));
+        }
+        _ => {}
+    }
+    check_missing(slices, &params)?;
+    let share = params.share() as u64;
+    if !slice_length.is_multiple_of(share) {
+        return Err(PhnxError::Format(format!(
+            "Slice length {} is not a multiple of {}",
+            slice_length, share
+        )));
+    }
+    let data_offset = if header.is_some() { SLICE_HEADER_SIZE } else { 0 };
+    if slice_length < data_offset {
+        return Err(PhnxError::Format("Slices are too short to hold a header".to_string()));
+    }
+
+    let missing = missing(slices);
+    let mut codec = params.codec();
+    let mut stats = GolayStats::default();
+    let mut health = SliceHealth {
+        slice_errors: vec![SliceErrors::default(); slice_count],
+        missing: slices.iter().map(Option::is_none).collect(),
+        header_damaged: vec![false; slice_count],
+        ..SliceHealth::default()
+    };
+    if let Some(header) = header {
+        for (i, slice) in slices.iter_mut().enumerate() {
+            if let Some(s) = slice {
+                let mut encoded = [0u8; SLICE_HEADER_SIZE as usize];
+                s.read_exact(&mut encoded).map_err(|e| {
+                    PhnxError::io(format!("Error reading from slice {}", slice_letter(i)), e)
+                })?;
+                // Kept apart, a lost header can be rewritten and does not make the set
+                // uncorrectable
+                let mut header_gc = GolayCode::new();
+                match decode_header(&encoded, &mut header_gc) {
+                    Some((h, errors)) if h == header.for_slice(i) => {
+                        health.slice_errors[i].merge(&errors);
+                        stats.merge(&header_gc.stats);
+                    }
+                    _ => health.header_damaged[i] = true,
+                }
+            }
+        }
+        write_headers(outputs, header, &GolayCode::new())?;
+    }
+    // Progress is reported once per chunk's worth of blocks
+    let block_size = params.block_size();
+    let progress_step = params.slice_bytes(CHUNK_SIZE) as u64;
+    let mut received = vec![0u8; share as usize * slice_count];
+    let mut errors = vec![0u8; received.len()];
+    let mut decoded = vec![0u8; block_size];
+    let mut done = data_offset;
+    while done < slice_length {
+        read_block(slices, share as usize, &mut received)?;
+        let correctable = codec.decode_block(&received, &missing, &mut decoded, &mut errors);
+        record_errors(&mut health.slice_errors, share as usize, done, &errors);
+        if !correctable {
+            let stream_offset = (done - data_offset) / share * block_size as u64;
+            add_damage(
+                &mut health.damaged,
+                stream_offset..stream_offset + block_size as u64,
+            );
+        }
+        codec.encode_block(&decoded, &mut received);
+        write_block(outputs, share as usize, &received)?;
+
+        done += share;
+        if (done - data_offset).is_multiple_of(progress_step) || done == slice_length {
+            if let Some(f) = progress.as_mut() {
+                f(done, slice_length);
+            }
+        }
+    }
+    flush_slices(outputs)?;
+
+    stats.merge(codec.stats());
+    health.stats = stats;
+    Ok(health)
+}
+
+/// Reads every codeword of the slices, corrects it, and writes it re-encoded to those
+/// outputs that are not None. Works on ciphertext, so no key is needed.
+/// Fails if some codewords are uncorrectable, since outputs would then be wrong.
+pub fn regenerate_slices<R: Read, W: Write>(
+    slices: &mut [Option<R>],
+    slice_length: u64,
+    header: Option<&SliceHeader>,
+    outputs: &mut [Option<W>],
+    progress: Option<&mut dyn FnMut(u64, u64)>,
+) -> Result<SliceHealth, PhnxError> {
+    let health = scan_slices(slices, slice_length, header, outputs, progress)?;
+    if health.stats.uncorrectable_codewords != 0 {
+        return Err(PhnxError::Uncorrectable(format!(
+            "{}, cannot regenerate slices",
+            health.stats
+        )));
+    }
+    Ok(health)
+}
+
+/// Reads and corrects every codeword of the slices without decrypting or writing anything
+pub fn verify_slices<R: Read>(
+    slices: &mut [Option<R>],
+    slice_length: u64,
+    header: Option<&SliceHeader>,
+    progress: Option<&mut dyn FnMut(u64, u64)>,
+) -> Result<SliceHealth, PhnxError> {
+    let mut no_outputs: Vec<Option<io::Sink>> = slices.iter().map(|_| None).collect();
+    scan_slices(slices, slice_length, header, &mut no_outputs, progress)
+}
+
+/// Keystream for the 24 byte suffix, taken at nonce=-1, counter=-1 and -2
+fn suffix_keystream(schedule: &[u64; 34]) -> [u64; 3] {
+    let nonce_ctr_m1 = [0xffffffffffffffffu64, 0xffffffffffffffffu64];
+    let nonce_ctr_m2 = [0xffffffffffffffffu64, 0xfffffffffffffffeu64];
+    let gamma1 = speck::speck_encrypt(&nonce_ctr_m1, schedule);
+    let gamma2 = speck::speck_encrypt(&nonce_ctr_m2, schedule);
+    [gamma1[0], gamma1[1], gamma2[0]]
+}
+
+/// Poly1305 key for one chunk or the trailer, taken from two keystream blocks at counters with
+/// the top bit set, which data encryption never reaches
+fn mac_key(schedule: &[u64; 34], nonce: u64, counter: u64) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    for i in 0..2 {
+        let gamma = speck::speck_encrypt(&[nonce, counter + i as u64], schedule);
+        key[i * 16..i * 16 + 8].copy_from_slice(&gamma[0].to_le_bytes());
+        key[i * 16 + 8..i * 16 + 16].copy_from_slice(&gamma[1].to_le_bytes());
+    }
+    key
+}
+
+/// Tag of the ciphertext of one chunk. The key depends on the chunk index, so chunks cannot
+/// be swapped, and the nonce ties them to one file.
+fn chunk_tag(schedule: &[u64; 34], nonce: u64, index: u64, ciphertext: &[u8]) -> [u8; 16] {
+    let mut mac = Poly1305::new(&mac_key(schedule, nonce, MAC_COUNTER | (index * 2)));
+    mac.update(ciphertext);
+    mac.finalize()
+}
+
+/// Tag of the trailer blocks: the metadata blocks before it, then those that follow it: the
+/// nonce block, the parameters block, and the encrypted suffix, which holds the length, so
+/// files cannot be truncated or extended. Key slots in between carry tags of their own and
+/// are left out, so one damaged beyond repair cannot stop the others.
+fn trailer_tag(schedule: &[u64; 34], nonce: u64, blocks: &[&[u8; 24]]) -> [u8; 16] {
+    let mut mac = Poly1305::new(&mac_key(schedule, nonce, TRAILER_MAC_COUNTER));
+    for block in blocks {
+        mac.update(*block);
+    }
+    mac.finalize()
+}
+
+/// 16 byte tag padded to a whole Golay block pair
+fn tag_block(tag: &[u8; 16]) -> [u8; 24] {
+    let mut block = [0u8; 24];
+    block[..16].copy_from_slice(tag);
+    block
+}
+
+fn block_tag(block: &[u8; 24]) -> &[u8; 16] {
+    block[..16].try_into().unwrap()
+}
+
+/// Metadata blocks: a 32-bit little-endian length and the serialized metadata, zero-padded to
+/// whole blocks and encrypted at METADATA_COUNTER. No metadata takes no blocks.
+fn metadata_blocks(
+    metadata: Option<&FileMetadata>,
+    nonce: u64,
+    schedule: &[u64; 34],
+) -> Vec<[u8; 24]> {
+    let Some(metadata) = metadata else {
+        return Vec::new();
+    };
+    let serialized = metadata.to_bytes();
+    let mut bytes = (serialized.len() as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(&serialized);
+    bytes.resize(bytes.len().next_multiple_of(24), 0);
+    speck::speck_ctr(&mut bytes, &mut metadata_counter(nonce), schedule);
+    bytes
+        .chunks_exact(24)
+        .map(|block| block.try_into().unwrap())
+        .collect()
+}
+
+fn parse_metadata_blocks(
+    blocks: &[[u8; 24]],
+    nonce: u64,
+    schedule: &[u64; 34],
+) -> Result<Option<FileMetadata>, PhnxError> {
+    if blocks.is_empty() {
+        return Ok(None);
+    }
+    let mut bytes = blocks.concat();
+    speck::speck_ctr(&mut bytes, &mut metadata_counter(nonce), schedule);
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    if len > bytes.len() - 4 {
+        return Err(PhnxError::Format("File metadata is malformed".to_string()));
+    }
+    FileMetadata::from_bytes(&bytes[4..4 + len]).map(Some)
+}
+
+fn metadata_counter(nonce: u64) -> [u64; 8] {
+    let counter = METADATA_COUNTER;
+    [nonce, nonce, nonce, nonce, counter, counter + 1, counter + 2, counter + 3]
+}
+
+/// Bytes of each slice that hold the chunks of a plaintext this long, with their tags
+fn data_slice_bytes(params: &CodecParams, length: u64) -> u64 {
+    let tag_size = params.slice_bytes(24) as u64;
+    let full_chunks = length / CHUNK_SIZE as u64;
+    let last_chunk = (length % CHUNK_SIZE as u64) as usize;
+    let mut total = full_chunks.saturating_mul(params.slice_bytes(CHUNK_SIZE) as u64 + tag_size);
+    if last_chunk != 0 {
+        total = total.saturating_add(params.slice_bytes(last_chunk) as u64 + tag_size);
+    }
+    total
+}
+
+/// Nonce block: 16 random bytes from which the file key is derived, then 8 zero bytes
+fn nonce_block(file_nonce: &[u8; 16]) -> [u8; 24] {
+    let mut block = [0u8; 24];
+    block[..16].copy_from_slice(file_nonce);
+    block
+}
+
+/// Parameters block: magic, format version, the number of key slots, and 18 zero bytes
+fn params_block(key_slots: u8) -> [u8; 24] {
+    let mut block = [0u8; 24];
+    block[0..4].copy_from_slice(PARAMS_MAGIC);
+    block[4] = FORMAT_VERSION;
+    block[5] = key_slots;
+    block
+}
+
+/// Number of key slots the parameters block tells of. None if the block does not start with
+/// the magic, which means a version 1 file.
+fn parse_params_block(block: &[u8; 24]) -> Result<Option<u8>, PhnxError> {
+    if block[0..4] != *PARAMS_MAGIC {
+        return Ok(None);
+    }
+    if block[4] != FORMAT_VERSION {
+        return Err(PhnxError::Format(format!(
+            "Unsupported format version {}",
+            block[4]
+        )));
+    }
+    if block[5] == 0 {
+        return Err(PhnxError::Format("File has no key slots".to_string()));
+    }
+    Ok(Some(block[5]))
+}
+
+/// Data key of the first key slot that a key from derive_key opens. Slots damaged beyond
+/// repair are passed over, and only blamed if no other slot opens. So are slots of an unknown
+/// kind or cost, or whose key cannot be derived, and their error is returned only if no slot
+/// could be tried at all.
+fn open_key_slots(
+    blocks: &[Option<[u8; 24]>],
+    mut derive_key: impl FnMut(&Wrapping) -> Result<Option<Key>, PhnxError>,
+) -> Result<[u8; 32], PhnxError> {
+    let mut damaged = false;
+    let mut tried = false;
+    let mut unusable = None;
+    for slot_blocks in blocks.chunks(KEY_SLOT_SIZE / 24) {
+        let mut bytes = [0u8; KEY_SLOT_SIZE];
+        if slot_blocks.iter().any(|b| b.is_none()) {
+            damaged = true;
+            continue;
+        }
+        for (chunk, block) in bytes.chunks_mut(24).zip(slot_blocks.iter().flatten()) {
+            chunk.copy_from_slice(block);
+        }
+        let slot = match KeySlot::from_bytes(&bytes) {
+            Ok(slot) => slot,
+            Err(e) => {
+                unusable.get_or_insert(e);
+                continue;
+            }
+        };
+        let key = match derive_key(&slot.wrapping) {
+            Ok(key) => key,
+            Err(e) => {
+                unusable.get_or_insert(e);
+                continue;
+            }
+        };
+        tried = true;
+        if let Some(data_key) = key.and_then(|key| slot.unwrap(&key)) {
+            return Ok(data_key);
+        }
+    }
+    if damaged {
+        return Err(PhnxError::Uncorrectable(
+            "Key slots are damaged beyond repair".to_string(),
+        ));
+    }
+    match unusable {
+        Some(e) if !tried => Err(e),
+        _ => Err(PhnxError::WrongPassword),
+    }
+}
+
+/// Number of key slots for the parameters block, fails past what it can hold
+fn key_slot_count(slots: usize) -> Result<u8, PhnxError> {
+    u8::try_from(slots)
+        .map_err(|_| PhnxError::Format("A file has at most 255 key slots".to_string()))
+}
+
+/// Seeks all slices to given offset, then reads and decodes one 24 byte block.
+/// Returns None if it has uncorrectable codewords.
+fn read_trailer_block<R: Read + Seek>(
+    slices: &mut [Option<R>],
+    slice_offset: u64,
+    codec: &mut dyn Codec,
+    slice_errors: &mut [SliceErrors],
+) -> Result<Option<[u8; 24]>, PhnxError> {
+    for (i, slice) in slices.iter_mut().enumerate() {
+        if let Some(s) = slice {
+            s.seek(SeekFrom::Start(slice_offset)).map_err(|e| {
+                PhnxError::io(format!("Error seeking in slice {}", slice_letter(i)), e)
+            })?;
+        }
+    }
+    let mut block = [0u8; 24];
+    let damaged = read_and_decode(&mut block, 24, slices, codec, slice_errors, slice_offset)?;
+    Ok(if damaged.is_empty() { Some(block) } else { None })
+}
+
+/// Header at the start of every slice after version 1, so that a slice can be identified on
+/// its own: which set it belongs to, its place in the set, and how the set was encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SliceHeader {
+    pub version: u8,
+    /// Place in the set, 0 for slice A
+    pub index: u8,
+    pub slice_count: u8,
+    pub codec: u8,
+    /// Reed-Solomon slices that hold parity, 0 for Golay
+    pub parity_slices: u8,
+    /// Random, shared by all slices of a set
+    pub set_id: [u8; 16],
+    /// Plaintext bytes per authenticated chunk
+    pub chunk_size: u32,
+}
+
+impl SliceHeader {
+    /// Header of slice A of a new set encoded as given, with a random id
+    fn new_set(params: &CodecParams) -> Result<Self, PhnxError> {
+        let mut set_id = [0u8; 16];
+        random::fill(&mut set_id)?;
+        Ok(SliceHeader {
+            version: FORMAT_VERSION,
+            index: 0,
+            slice_count: params.slice_count,
+            codec: params.codec,
+            parity_slices: params.parity_slices,
+            set_id,
+            chunk_size: CHUNK_SIZE as u32,
+        })
+    }
+
+    /// Codec of the set and how it is split
+    pub fn codec_params(&self) -> CodecParams {
+        CodecParams {
+            codec: self.codec,
+            slice_count: self.slice_count,
+            parity_slices: self.parity_slices,
+        }
+    }
+
+    /// Header of another slice of the same set
+    pub fn for_slice(&self, index: usize) -> Self {
+        SliceHeader {
+            index: index as u8,
+            ..*self
+        }
+    }
+
+    /// Magic, version, index, slice count, codec, set id, chunk size, parity slices, 15
+    /// reserved bytes written as zeroes and ignored on reading, then CRC32C of all that. Golay
+    /// decoding alone would accept a run of zeroes, which are valid codewords.
+    fn to_bytes(self) -> [u8; 48] {
+        let mut bytes = [0u8; 48];
+        bytes[0..4].copy_from_slice(PARAMS_MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self.index;
+        bytes[6] = self.slice_count;
+        bytes[7] = self.codec;
+        bytes[8..24].copy_from_slice(&self.set_id);
+        bytes[24..28].copy_from_slice(&self.chunk_size.to_le_bytes());
+        bytes[28] = self.parity_slices;
+        let crc32c = header_crc32c(&bytes);
+        bytes[44..48].copy_from_slice(&crc32c.to_le_bytes());
+        bytes
+    }
+
+    /// None if the bytes do not start with the magic or the CRC32C does not match
+    fn from_bytes(bytes: &[u8; 48]) -> Option<Self> {
+        let crc32c = u32::from_le_bytes(bytes[44..48].try_into().unwrap());
+        (bytes[0..4] == *PARAMS_MAGIC && crc32c == header_crc32c(bytes)).then(|| SliceHeader {
+            version: bytes[4],
+            index: bytes[5],
+            slice_count: bytes[6],
+            codec: bytes[7],
+            parity_slices: bytes[28],
+            set_id: bytes[8..24].try_into().unwrap(),
+            chunk_size: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+        })
+    }
+
+    /// Every 3 header bytes make 2 codewords, each stored as 3 little-endian bytes
+    fn encode(self, gc: &GolayCode) -> [u8; SLICE_HEADER_SIZE as usize] {
+        let bytes = self.to_bytes();
+        let mut encoded = [0u8; SLICE_HEADER_SIZE as usize];
+        for (k, b) in bytes.chunks_exact(3).enumerate() {
+            let data = [
+                b[0] as u32 | ((b[1] as u32 & 0xf) << 8),
+                (b[1] as u32 >> 4) | ((b[2] as u32) << 4),
+            ];
+            for (c, x) in data.into_iter().enumerate() {
+                let offset = (k * 2 + c) * 3;
+                encoded[offset..offset + 3].copy_from_slice(&gc.encode(x).to_le_bytes()[..3]);
+            }
+        }
+        encoded
+    }
+
+    /// Fails unless this implementation can decode the set
+    fn check_supported(&self) -> Result<(), PhnxError> {
+        if self.version != FORMAT_VERSION {
+            return Err(PhnxError::Format(format!(
+                "Unsupported format version {}",
+                self.version
+            )));
+        }
+        self.codec_params().check()?;
+        if self.chunk_size != CHUNK_SIZE as u32 {
+            return Err(PhnxError::Format(format!(
+                "Unsupported chunk size {}",
+                self.chunk_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn header_crc32c(bytes: &[u8; 48]) -> u32 {
+    let mut crc32c = Crc32c::new();
+    crc32c.update_slice(&bytes[..44]);
+    crc32c.finalize()
+}
+
+/// Decodes a header read from one slice, returning it with the bits corrected at each offset
+/// of that slice. None if some codeword is uncorrectable, or the header does not check out.
+fn decode_header(
+    encoded: &[u8; SLICE_HEADER_SIZE as usize],
+    gc: &mut GolayCode,
+) -> Option<(SliceHeader, SliceErrors)> {
+    let mut bytes = [0u8; 48];
+    let mut errors = SliceErrors::default();
+    for (k, b) in bytes.chunks_exact_mut(3).enumerate() {
+        let mut data = [0u32; 2];
+        for (c, d) in data.iter_mut().enumerate() {
+            let offset = (k * 2 + c) * 3;
+            let codeword =
+                u32::from_le_bytes([encoded[offset], encoded[offset + 1], encoded[offset + 2], 0]);
+            let (x, pattern) = gc.decode_with_error_pattern(codeword, 0);
+            if x < 0 {
+                return None;
+            }
+            for t in 0..3 {
+                let bits = (pattern >> (t * 8)) as u8;
+                if bits != 0 {
+                    errors.record((offset + t) as u64, bits.count_ones() as u64);
+                }
+            }
+            *d = x as u32;
+        }
+        b[0] = data[0] as u8;
+        b[1] = ((data[0] >> 8) | (data[1] << 4)) as u8;
+        b[2] = (data[1] >> 4) as u8;
+    }
+    SliceHeader::from_bytes(&bytes).map(|h| (h, errors))
+}
+
+fn write_headers<W: Write>(
+    slices: &mut [Option<W>],
+    header: &SliceHeader,
+    gc: &GolayCode,
+) -> Result<(), PhnxError> {
+    for (i, slice) in slices.iter_mut().enumerate() {
+        if let Some(f) = slice {
+            f.write_all(&header.for_slice(i).encode(gc)).map_err(|e| {
+                PhnxError::io(format!("Error writing slice {}", slice_letter(i)), e)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Header found in a slice, with the bits corrected in it and the decoding statistics
+type HeaderRead = (SliceHeader, SliceErrors, GolayStats);
+
+/// Reads the header at the start of every available slice. Slices of version 1 have none, so
+/// to tell those apart from a damaged header, all slices must be looked at.
+fn read_headers<R: Read + Seek>(
+    slices: &mut [Option<R>],
+) -> Result<Vec<Option<HeaderRead>>, PhnxError> {
+    let mut headers = vec![None; slices.len()];
+    for (i, slice) in slices.iter_mut().enumerate() {
+        if let Some(s) = slice {
+            let mut encoded = [0u8; SLICE_HEADER_SIZE as usize];
+            let read = s
+                .seek(SeekFrom::Start(0))
+                .and_then(|_| s.read_exact(&mut encoded));
+            match read {
+                Ok(()) => {
+                    let mut gc = GolayCode::new();
+                    headers[i] = decode_header(&encoded, &mut gc).map(|(h, e)| (h, e, gc.stats));
+                }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                Err(e) => {
+                    return Err(PhnxError::io(
+                        format!("Error reading from slice {}", slice_letter(i)),
+                        e,
+                    ))
+                }
+            }
+        }
+    }
+    Ok(headers)
+}
+
+fn rewind_slices<R: Seek>(slices: &mut [Option<R>]) -> Result<(), PhnxError> {
+    for (i, slice) in slices.iter_mut().enumerate() {
+        if let Some(s) = slice {
+            s.seek(SeekFrom::Start(0)).map_err(|e| {
+                PhnxError::io(format!("Error seeking in slice {}", slice_letter(i)), e)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// What arrange_slices() did with a slice, places counting from 0 for slice A
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceNote {
+    /// Header of the slice given at `from` says it belongs at `to`, so it was moved there
+    Moved { from: usize, to: usize },
+    /// Header names another set, or the same set split or encoded differently, the slice is
+    /// left out
+    ForeignSet(usize),
+    /// Slice given at `from` belongs at `of`, where another slice was already placed, so it
+    /// is left out
+    Duplicate { from: usize, of: usize },
+    /// Header is damaged beyond repair, the slice is kept where it was given
+    HeaderDamaged(usize),
+}
+
+/// Slices put in place by their headers
+pub struct SliceSet<R> {
+    /// As many as the header says the set has, 8 for version 1
+    pub slices: Vec<Option<R>>,
+    /// Header the slices have in common, with index 0. None for version 1.
+    pub header: Option<SliceHeader>,
+    pub notes: Vec<SliceNote>,
+}
+
+/// Puts every slice at the place its header names, so renamed slices are still used, and
+/// leaves out slices of other sets. If slices disagree, the set most of them belong to wins.
+/// Slices without headers are returned as given. All slices are left at offset 0.
+pub fn arrange_slices<R: Read + Seek>(
+    slices: impl IntoIterator<Item = Option<R>>,
+) -> Result<SliceSet<R>, PhnxError> {
+    let mut slices: Vec<Option<R>> = slices.into_iter().collect();
+    let headers = read_headers(&mut slices)?;
+    rewind_slices(&mut slices)?;
+    let found: Vec<&SliceHeader> = headers.iter().flatten().map(|(h, ..)| h).collect();
+    let Some(header) = found
+        .iter()
+        .max_by_key(|h| found.iter().filter(|o| o.set_id == h.set_id).count())
+        .map(|h| h.for_slice(0))
+    else {
+        return Ok(SliceSet {
+            slices,
+            header: None,
+            notes: Vec::new(),
+        });
+    };
+    header.check_supported()?;
+
+    let slice_count = header.slice_count as usize;
+    let mut placed: Vec<Option<R>> = (0..slice_count).map(|_| None).collect();
+    let mut notes = Vec::new();
+    let mut unidentified = Vec::new();
+    for (i, slice) in slices.into_iter().enumerate() {
+        let Some(slice) = slice else { continue };
+        match &headers[i] {
+            Some((h, ..))
+                if h.set_id != header.set_id || h.codec_params() != header.codec_params() =>
+            {
+                notes.push(SliceNote::ForeignSet(i))
+            }
+            Some((h, ..)) if (h.index as usize) < slice_count => {
+                let to = h.index as usize;
+                if placed[to].is_some() {
+                    notes.push(SliceNote::Duplicate { from: i, of: to });
+                } else {
+                    if to != i {
+                        notes.push(SliceNote::Moved { from: i, to });
+                    }
+                    placed[to] = Some(slice);
+                }
+            }
+            _ => unidentified.push((i, slice)),
+        }
+    }
+    for (i, slice) in unidentified {
+        notes.push(SliceNote::HeaderDamaged(i));
+        if i >= slice_count {
+            notes.push(SliceNote::Duplicate { from: i, of: i });
+        } else if placed[i].is_none() {
+            placed[i] = Some(slice);
+        } else {
+            notes.push(SliceNote::Duplicate { from: i, of: i });
+        }
+    }
+    Ok(SliceSet {
+        slices: placed,
+        header: Some(header),
+        notes,
+    })
+}
+
+/// Reads until buffer is full or input ends, returns how many bytes were read
+fn read_chunk<R: Read>(input: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut done = 0;
+    while done < buffer.len() {
+        match input.read(&mut buffer[done..]) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+/// Encrypts a stream with Speck128/256 in CTR mode, authenticates every chunk with Poly1305,
+/// adds Golay or Reed-Solomon error correction bits, and splits the result into slices
+pub struct Encoder<'a> {
+    /// Keys that open a key slot each, with how they are found again
+    slots: Vec<(Key, Wrapping)>,
+    codec: Option<CodecParams>,
+    metadata: Option<FileMetadata>,
+    threads: usize,
+    progress: Option<&'a mut dyn FnMut(u64, u64)>,
+}
+
+impl<'a> Encoder<'a> {
+    /// Encrypts so that the given key as is opens the file
+    pub fn new(key: &Key) -> Self {
+        Encoder {
+            slots: vec![(key.clone(), Wrapping::Key)],
+            codec: None,
+            metadata: None,
+            threads: 1,
+            progress: None,
+        }
+    }
+
+    /// Encrypts so that password opens the file, deriving its key with the salt and cost
+    /// stored in the file
+    pub fn with_password(password: &[u8], params: KdfParams) -> Result<Self, PhnxError> {
+        Ok(Encoder {
+            slots: vec![(params.derive(password)?, Wrapping::Password(params))],
+            codec: None,
+            metadata: None,
+            threads: 1,
+            progress: None,
+        })
+    }
+
+    /// Lets the given key as is open the file too, in a key slot of its own
+    pub fn add_key(mut self, key: &Key) -> Self {
+        self.slots.push((key.clone(), Wrapping::Key));
+        self
+    }
+
+    /// Lets password open the file too, in a key slot of its own with its own salt and cost
+    pub fn add_password(mut self, password: &[u8], params: KdfParams) -> Result<Self, PhnxError> {
+        self.slots.push((params.derive(password)?, Wrapping::Password(params)));
+        Ok(self)
+    }
+
+    /// Encrypts so that only the private key of the given X25519 public key opens the file,
+    /// which needs no secret to encode with
+    pub fn for_recipient(public_key: &[u8; 32]) -> Result<Self, PhnxError> {
+        let (key, ephemeral) = keyslot::recipient_key(public_key)?;
+        Ok(Encoder {
+            slots: vec![(key, Wrapping::Recipient { ephemeral })],
+            codec: None,
+            metadata: None,
+            threads: 1,
+            progress: None,
+        })
+    }
+
+    /// Lets the private key of the given X25519 public key open the file too, in a key slot
+    /// of its own
+    pub fn add_recipient(mut self, public_key: &[u8; 32]) -> Result<Self, PhnxError> {
+        let (key, ephemeral) = keyslot::recipient_key(public_key)?;
+        self.slots.push((key, Wrapping::Recipient { ephemeral }));
+        Ok(self)
+    }
+
+    /// Calls `f(bytes_done, bytes_total)` after every chunk
+    pub fn progress(mut self, f: &'a mut dyn FnMut(u64, u64)) -> Self {
+        self.progress = Some(f);
+        self
+    }
+
+    /// Encodes with the given codec, which decides how many slices there must be. By default
+    /// Golay (24,12,8) is spread over as many slices as there are outputs.
+    pub fn codec(mut self, params: CodecParams) -> Self {
+        self.codec = Some(params);
+        self
+    }
+
+    /// Encrypts and encodes chunks on this many worker threads, with input read on another
+    /// one. The slices come out the same for any number; 1, the default, uses no threads.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Stores attributes of the original file in the trailer, encrypted and authenticated
+    /// like the data, for the decoder to return in Report::metadata
+    pub fn metadata(mut self, metadata: FileMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Encodes input from its current position to the end, writing one slice to each of the
+    /// given outputs, as many as the codec has slices. Data is encrypted with a key derived
+    /// from a random data key and a random 128-bit file nonce, plus a random 64-bit nonce in
+    /// every keystream block. The data key is wrapped in a key slot for every recipient.
+    pub fn encode<R: Read + Seek + Send, W: Write>(
+        &mut self,
+        mut input: R,
+        slices: impl IntoIterator<Item = W>,
+    ) -> Result<Report, PhnxError> {
+        let start = input
+            .stream_position()
+            .map_err(|e| PhnxError::io("Cannot determine file length", e))?;
+        let end = input
+            .seek(SeekFrom::End(0))
+            .map_err(|e| PhnxError::io("Cannot determine file length", e))?;
+        input
+            .seek(SeekFrom::Start(start))
+            .map_err(|e| PhnxError::io("Cannot determine file length", e))?;
+        let length = end.saturating_sub(start);
+        self.encode_chunks(input.take(length), length, slices)
+    }
+
+    /// Same as encode(), for input that cannot seek, such as a pipe. It is read until it ends,
+    /// and progress is reported with a total of 0, since the length is only known then.
+    pub fn encode_stream<R: Read + Send, W: Write>(
+        &mut self,
+        input: R,
+        slices: impl IntoIterator<Item = W>,
+    ) -> Result<Report, PhnxError> {
+        self.encode_chunks(input, 0, slices)
+    }
+
+    /// Nothing but the progress report needs the length up front: every chunk carries its own
+    /// tag, and the length goes into the trailer once input ends
+    fn encode_chunks<R: Read + Send, W: Write>(
+        &mut self,
+        mut input: R,
+        expected_length: u64,
+        slices: impl IntoIterator<Item = W>,
+    ) -> Result<Report, PhnxError> {
+        let mut slices: Vec<Option<W>> = slices.into_iter().map(Some).collect();
+        let params = match self.codec {
+            Some(params) => params,
+            None => CodecParams::golay(slices.len())?,
+        };
+        params.check()?;
+        if params.slice_count() != slices.len() {
+            return Err(PhnxError::Format(format!(
+                "{} needs {} slices, got {}",
+                params,
+                params.slice_count,
+                slices.len()
+            )));
+        }
+        let key_slots = key_slot_count(self.slots.len())?;
+        let mut data_key = [0u8; 32];
+        random::fill(&mut data_key)?;
+        let slots = self.wrap_slots(&data_key)?;
+        let mut file_nonce = [0u8; 16];
+        random::fill(&mut file_nonce)?;
+        let nonce = random::random_u64()?;
+        let file_key = Key::new(&data_key).file_key(&file_nonce);
+        let schedule = file_key.schedule();
+        let codec = params.codec();
+        write_headers(&mut slices, &SliceHeader::new_set(&params)?, &GolayCode::new())?;
+
+        let mut crc32c = Crc32c::new();
+        let mut chunk_index = 0;
+        let mut input_ended = false;
+        let mut length = 0;
+        let progress = &mut self.progress;
+        pipeline::run(
+            self.threads,
+            || {
+                if input_ended {
+                    return Ok(None);
+                }
+                let mut chunk = vec![0u8; CHUNK_SIZE];
+                let chunk_size = read_chunk(&mut input, &mut chunk)
+                    .map_err(|e| PhnxError::io("Error reading input", e))?;
+                // Only the last chunk may be short
+                input_ended = chunk_size < CHUNK_SIZE;
+                if chunk_size == 0 {
+                    return Ok(None);
+                }
+                chunk.truncate(chunk_size);
+                crc32c.update_slice(&chunk);
+                chunk_index += 1;
+                Ok(Some((chunk_index - 1, chunk)))
+            },
+            |(index, chunk)| {
+                let chunk_size = chunk.len();
+                Ok((chunk_size, encode_chunk(chunk, index, nonce, schedule, &params)?))
+            },
+            |(chunk_size, parts)| {
+                write_slice_parts(&mut slices, &parts)?;
+                length += chunk_size as u64;
+                if let Some(f) = progress.as_mut() {
+                    f(length, expected_length);
+                }
+                Ok(())
+            },
+        )?;
+
+        let crc32c_val = crc32c.finalize();
+        let gamma = suffix_keystream(schedule);
+        let suffix = [
+            (((crc32c_val as u64) << 32) | (crc32c_val as u64)) ^ gamma[0],
+            nonce ^ gamma[1],
+            length ^ gamma[2],
+        ];
+        let mut suffix_bytes = [0u8; 24];
+        suffix_bytes[0..8].copy_from_slice(&suffix[0].to_le_bytes());
+        suffix_bytes[8..16].copy_from_slice(&suffix[1].to_le_bytes());
+        suffix_bytes[16..24].copy_from_slice(&suffix[2].to_le_bytes());
+        let nonce_bytes = nonce_block(&file_nonce);
+        let params_bytes = params_block(key_slots);
+        let metadata = metadata_blocks(self.metadata.as_ref(), nonce, schedule);
+        let mut blocks: Vec<&[u8; 24]> = metadata.iter().collect();
+        blocks.extend([&nonce_bytes, &params_bytes, &suffix_bytes]);
+        let tag = trailer_tag(schedule, nonce, &blocks);
+        for block in &metadata {
+            encode_and_write(block, 24, &mut slices, codec.as_ref())?;
+        }
+        encode_and_write(&tag_block(&tag), 24, &mut slices, codec.as_ref())?;
+        for slot in &slots {
+            for block in slot.to_bytes().chunks(24) {
+                encode_and_write(block, 24, &mut slices, codec.as_ref())?;
+            }
+        }
+        encode_and_write(&nonce_bytes, 24, &mut slices, codec.as_ref())?;
+        encode_and_write(&params_bytes, 24, &mut slices, codec.as_ref())?;
+        encode_and_write(&suffix_bytes, 24, &mut slices, codec.as_ref())?;
+
+        flush_slices(&mut slices)?;
+
+        Ok(Report {
+            length,
+            crc32c: crc32c_val,
+            stats: codec.stats().clone(),
+            ..Report::default()
+        })
+    }
+
+    /// Key slot with data_key for every password, key, and recipient
+    fn wrap_slots(&self, data_key: &[u8; 32]) -> Result<Vec<KeySlot>, PhnxError> {
+        self.slots
+            .iter()
+            .map(|(key, wrapping)| KeySlot::wrap(data_key, key, *wrapping))
+            .collect()
+    }
+}
+
+/// New end of every slice of a set, which changes who can open it, see Decoder::rekey()
+#[derive(Debug, Clone, Default)]
+pub struct Trailer {
+    /// Offset in every slice where the old trailer starts, and the new one goes
+    pub offset: u64,
+    /// Bytes of each slice from the offset on, starting with A. Slices end after them.
+    pub slices: Vec<Vec<u8>>,
+}
+
+/// What of a trailer stays the same when the key slots change
+#[derive(Debug, Clone)]
+struct TrailerParts {
+    data_key: [u8; 32],
+    /// Offset in every slice of the trailer tag
+    offset: u64,
+    metadata: Vec<[u8; 24]>,
+    /// Blocks of the key slots there are, None for those damaged beyond repair
+    slots: Vec<Option<[u8; 24]>>,
+    nonce: [u8; 24],
+    suffix: [u8; 24],
+}
+
+/// Reads enough slices of a set, corrects errors, and decrypts the original stream
+pub struct Decoder<'a, R> {
+    key: Key,
+    slices: Vec<Option<R>>,
+    codec: CodecParams,
+    stats: GolayStats,
+    slice_errors: Vec<SliceErrors>,
+    expected_crc32c: u32,
+    nonce: u64,
+    length: u64,
+    /// Past the header at the start of every slice, 0 for version 1
+    data_offset: u64,
+    /// Tag after every chunk, not in version 1
+    authenticated: bool,
+    metadata: Option<FileMetadata>,
+    /// For rekey(), None for version 1
+    trailer: Option<TrailerParts>,
+    /// Plaintext bytes to decode, all of them by default
+    range: Option<Range<u64>>,
+    keep: Option<DamageFill>,
+    threads: usize,
+    progress: Option<&'a mut dyn FnMut(u64, u64)>,
+}
+
+impl<'a, R: Read + Seek> Decoder<'a, R> {
+    /// Takes the slices of a set in order from A, None standing for a missing slice, and
+    /// decrypts with the given key as is. Reads the suffix and validates the key before any
+    /// output is produced.
+    /// Fails if slice headers show that slices are out of place or from different sets, see
+    /// arrange_slices().
+    pub fn new(
+        key: &Key,
+        slices: impl IntoIterator<Item = Option<R>>,
+    ) -> Result<Self, PhnxError> {
+        Self::open(slices.into_iter().collect(), |wrapping| match wrapping {
+            Wrapping::Recipient { .. } => Ok(None),
+            _ => Ok(Some(key.clone())),
+        })
+    }
+
+    /// Same as new(), derives the key from password with parameters stored in the file or
+    /// its key slots. Files or slots without them use the password as the key.
+    pub fn with_password(
+        password: &[u8],
+        slices: impl IntoIterator<Item = Option<R>>,
+    ) -> Result<Self, PhnxError> {
+        Self::open(slices.into_iter().collect(), |wrapping| match wrapping {
+            Wrapping::Key => Ok(Some(Key::from_password(password))),
+            Wrapping::Password(params) => params.derive(password).map(Some),
+            Wrapping::Recipient { .. } => Ok(None),
+        })
+    }
+
+    /// Same as new(), opens a key slot made for the X25519 public key of private_key
+    pub fn with_private_key(
+        private_key: &[u8; 32],
+        slices: impl IntoIterator<Item = Option<R>>,
+    ) -> Result<Self, PhnxError> {
+        Self::open(slices.into_iter().collect(), |wrapping| match wrapping {
+            Wrapping::Recipient { ephemeral } => {
+                Ok(keyslot::recipient_unwrap_key(private_key, ephemeral))
+            }
+            _ => Ok(None),
+        })
+    }
+
+    fn open(
+        mut slices: Vec<Option<R>>,
+        mut derive_key: impl FnMut(&Wrapping) -> Result<Option<Key>, PhnxError>,
+    ) -> Result<Self, PhnxError> {
+        let slice_count = slices.len();
+        let headers = read_headers(&mut slices)?;
+        // Slices without a readable header can only be decoded as Golay, the codec of
+        // version 1
+        let codec_params = match headers.iter().flatten().next() {
+            Some((h, ..)) => {
+                h.check_supported()?;
+                h.codec_params()
+            }
+            None => CodecParams::golay(slice_count)?,
+        };
+        if codec_params.slice_count() != slice_count {
+            return Err(PhnxError::Format(format!(
+                "Slices belong to a set of {}, got {}",
+                codec_params.slice_count, slice_count
+            )));
+        }
+        check_missing(&slices, &codec_params)?;
+        let mut codec = codec_params.codec();
+        let mut stats = GolayStats::default();
+        let mut slice_errors = vec![SliceErrors::default(); slice_count];
+        // Every 24 byte trailer block takes this many bytes of each slice
+        let block_share = codec_params.slice_bytes(24) as u64;
+        let mut slice_length = 0;
+        for (i, slice) in slices.iter_mut().enumerate() {
+            if let Some(s) = slice {
+                slice_length = s.seek(SeekFrom::End(0)).map_err(|e| {
+                    PhnxError::io(format!("Error seeking in slice {}", slice_letter(i)), e)
+                })?;
+            }
+        }
+        if slice_length < block_share {
+            return Err(PhnxError::Format("Slices are too short to hold a suffix".to_string()));
+        }
+
+        // Suffix is the last 2 Golay blocks = 48 bytes, 6 bytes per slice in a set of 8
+        let suffix_offset = slice_length - block_share;
+        let suffix_bytes =
+            read_trailer_block(&mut slices, suffix_offset, codec.as_mut(), &mut slice_errors)?
+                .ok_or_else(|| {
+                    PhnxError::Uncorrectable(
+                        "Suffix is damaged beyond repair, cannot tell length and nonce"
+                            .to_string(),
+                    )
+                })?;
+
+        // Parameters block precedes it after version 1. In version 1 the same place holds
+        // data, so errors found there are only kept once the magic matches.
+        let mut key_slots = None;
+        let mut params_bytes = [0u8; 24];
+        let mut params_damaged = false;
+        if slice_length >= 2 * block_share {
+            let mut probe_codec = codec_params.codec();
+            let mut probe_errors = vec![SliceErrors::default(); slice_count];
+            let block = read_trailer_block(
+                &mut slices,
+                slice_length - 2 * block_share,
+                probe_codec.as_mut(),
+                &mut probe_errors,
+            )?;
+            match block {
+                Some(block) => {
+                    key_slots = parse_params_block(&block)?;
+                    if key_slots.is_some() {
+                        params_bytes = block;
+                        stats.merge(probe_codec.stats());
+                        for i in 0..slice_count {
+                            slice_errors[i].merge(&probe_errors[i]);
+                        }
+                    }
+                }
+                None => params_damaged = true,
+            }
+        }
+
+        // Version 1 has no header to tell of other than 8 Golay slices
+        if key_slots.is_none() && codec_params != CodecParams::default() {
+            return Err(PhnxError::Format(format!(
+                "Slices of version 1 come in sets of {}, got {}",
+                DEFAULT_SLICE_COUNT, slice_count
+            )));
+        }
+
+        let mut trailer_offset = 2 * block_share;
+        let mut data_offset = 0;
+        let mut current = None;
+        if let Some(key_slots) = key_slots {
+            // The nonce block precedes the parameters block
+            trailer_offset += block_share;
+            let nonce = (slice_length >= trailer_offset)
+                .then(|| {
+                    let offset = slice_length - trailer_offset;
+                    read_trailer_block(&mut slices, offset, codec.as_mut(), &mut slice_errors)
+                })
+                .transpose()?
+                .flatten()
+                .ok_or_else(|| {
+                    PhnxError::Uncorrectable("File nonce is damaged beyond repair".to_string())
+                })?;
+
+            // Key slots precede the nonce block. Each is read on its own, since one that
+            // opens is enough.
+            let slot_share = (KEY_SLOT_SIZE / 24) as u64 * block_share;
+            if slice_length < trailer_offset + key_slots as u64 * slot_share {
+                return Err(PhnxError::Format(
+                    "Slices are too short to hold the key slots".to_string(),
+                ));
+            }
+            let mut slot_blocks = Vec::new();
+            for _ in 0..key_slots as usize * KEY_SLOT_SIZE / 24 {
+                trailer_offset += block_share;
+                let offset = slice_length - trailer_offset;
+                slot_blocks.push(read_trailer_block(
+                    &mut slices,
+                    offset,
+                    codec.as_mut(),
+                    &mut slice_errors,
+                )?);
+            }
+            // Read backwards, put them back in file order
+            slot_blocks.reverse();
+
+            // The trailer tag precedes them
+            trailer_offset += block_share;
+            let tag = (slice_length >= trailer_offset)
+                .then(|| {
+                    let offset = slice_length - trailer_offset;
+                    read_trailer_block(&mut slices, offset, codec.as_mut(), &mut slice_errors)
+                })
+                .transpose()?
+                .flatten()
+                .ok_or_else(|| {
+                    PhnxError::Uncorrectable("Trailer MAC is damaged beyond repair".to_string())
+                })?;
+
+            // Every slice starts with a header, which must put it in its place
+            data_offset = SLICE_HEADER_SIZE;
+            if slice_length < data_offset + trailer_offset {
+                return Err(PhnxError::Format("Slices are too short to hold a header".to_string()));
+            }
+            let mut set_id = None;
+            for (i, header) in headers.iter().enumerate() {
+                let Some((h, errors, header_stats)) = header else {
+                    continue;
+                };
+                h.check_supported()?;
+                if h.codec_params() != codec_params {
+                    return Err(PhnxError::Format(format!(
+                        "Slice {} belongs to a set encoded with {}, not {}",
+                        slice_letter(i),
+                        h.codec_params(),
+                        codec_params
+                    )));
+                }
+                if h.index as usize != i {
+                    return Err(PhnxError::Format(format!(
+                        "Slice {} is slice {} of its set",
+                        slice_letter(i),
+                        slice_letter(h.index as usize)
+                    )));
+                }
+                if *set_id.get_or_insert(h.set_id) != h.set_id {
+                    return Err(PhnxError::Format(format!(
+                        "Slice {} belongs to a different set",
+                        slice_letter(i)
+                    )));
+                }
+                slice_errors[i].merge(errors);
+                stats.merge(header_stats);
+            }
+            current = Some((nonce, slot_blocks, tag));
+        }
+        let tag_offset = slice_length.saturating_sub(trailer_offset);
+
+        let mut data_key = [0u8; 32];
+        let key = match &current {
+            Some((nonce, slot_blocks, _)) => {
+                data_key = open_key_slots(slot_blocks, derive_key)?;
+                Key::new(&data_key).file_key(nonce[..16].try_into().unwrap())
+            }
+            None => derive_key(&Wrapping::Key)?.ok_or_else(|| {
+                PhnxError::Format("Slices of version 1 only open with a password".to_string())
+            })?,
+        };
+
+        // Decrypt suffix with nonce=-1, counter=-1, -2
+        let gamma = suffix_keystream(key.schedule());
+        let s0 = u64::from_le_bytes(suffix_bytes[0..8].try_into().unwrap()) ^ gamma[0];
+        let s1 = u64::from_le_bytes(suffix_bytes[8..16].try_into().unwrap()) ^ gamma[1];
+        let s2 = u64::from_le_bytes(suffix_bytes[16..24].try_into().unwrap()) ^ gamma[2];
+
+        let crc32c0 = s0 as u32;
+        let crc32c1 = (s0 >> 32) as u32;
+        if crc32c0 != crc32c1 {
+            if params_damaged {
+                return Err(PhnxError::Uncorrectable(
+                    "Key derivation parameters are damaged beyond repair".to_string(),
+                ));
+            }
+            return Err(PhnxError::WrongPassword);
+        }
+
+        let mut metadata = None;
+        let mut trailer = None;
+        if let Some((nonce, slot_blocks, tag_bytes)) = &current {
+            // Metadata blocks fill the space between the last chunk and the trailer tag,
+            // which only the length tells
+            let data_end = data_offset + data_slice_bytes(&codec_params, s2);
+            let metadata_end = slice_length - trailer_offset;
+            if data_end > metadata_end || !(metadata_end - data_end).is_multiple_of(block_share) {
+                return Err(PhnxError::Format(
+                    "Slice length does not match plaintext length".to_string(),
+                ));
+            }
+            let mut metadata_bytes = Vec::new();
+            for offset in (data_end..metadata_end).step_by(block_share as usize) {
+                let block =
+                    read_trailer_block(&mut slices, offset, codec.as_mut(), &mut slice_errors)?
+                        .ok_or_else(|| {
+                            PhnxError::Uncorrectable(
+                                "File metadata is damaged beyond repair".to_string(),
+                            )
+                        })?;
+                metadata_bytes.push(block);
+            }
+
+            let mut blocks: Vec<&[u8; 24]> = metadata_bytes.iter().collect();
+            blocks.extend([nonce, &params_bytes, &suffix_bytes]);
+            let tag = trailer_tag(key.schedule(), s1, &blocks);
+            if !tags_equal(&tag, block_tag(tag_bytes)) {
+                return Err(PhnxError::AuthFailed(Box::default()));
+            }
+            metadata = parse_metadata_blocks(&metadata_bytes, s1, key.schedule())?;
+            trailer = Some(TrailerParts {
+                data_key,
+                offset: tag_offset,
+                metadata: metadata_bytes,
+                slots: slot_blocks.clone(),
+                nonce: *nonce,
+                suffix: suffix_bytes,
+            });
+        }
+
+        for (i, slice) in slices.iter_mut().enumerate() {
+            if let Some(s) = slice {
+                s.seek(SeekFrom::Start(data_offset)).map_err(|e| {
+                    PhnxError::io(format!("Error seeking in slice {}", slice_letter(i)), e)
+                })?;
+            }
+        }
+
+        stats.merge(codec.stats());
+        Ok(Decoder {
+            key,
+            slices,
+            codec: codec_params,
+            stats,
+            slice_errors,
+            expected_crc32c: crc32c0,
+            nonce: s1,
+            length: s2,
+            data_offset,
+            authenticated: current.is_some(),
+            metadata,
+            trailer,
+            range: None,
+            keep: None,
+            threads: 1,
+            progress: None,
+        })
+    }
+
+    /// Plaintext length recorded in the suffix
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Attributes of the original file, if the encoder was given them
+    pub fn metadata(&self) -> Option<&FileMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Trailer that lets the passwords, keys, and recipients of encoder open the file instead
+    /// of those that can now, or besides them if keep_slots is set. Written over the end of
+    /// every slice, it changes the key without touching the data: the data key stays, only
+    /// the key slots that wrap it change. Fails for version 1, whose data is encrypted under
+    /// the password itself.
+    pub fn rekey(&self, encoder: &Encoder, keep_slots: bool) -> Result<Trailer, PhnxError> {
+        let Some(parts) = &self.trailer else {
+            return Err(PhnxError::Format(
+                "Slices of version 1 have no key slots, decode and encode them again"
+                    .to_string(),
+            ));
+        };
+        let mut slot_blocks = Vec::new();
+        if keep_slots {
+            if parts.slots.iter().any(Option::is_none) {
+                return Err(PhnxError::Uncorrectable(
+                    "Key slots are damaged beyond repair, cannot keep them".to_string(),
+                ));
+            }
+            slot_blocks.extend(parts.slots.iter().flatten().copied());
+        }
+        for slot in encoder.wrap_slots(&parts.data_key)? {
+            let bytes = slot.to_bytes();
+            slot_blocks.extend(bytes.chunks(24).map(|b| <[u8; 24]>::try_from(b).unwrap()));
+        }
+        let params_bytes = params_block(key_slot_count(slot_blocks.len() * 24 / KEY_SLOT_SIZE)?);
+
+        let mut blocks: Vec<&[u8; 24]> = parts.metadata.iter().collect();
+        blocks.extend([&parts.nonce, &params_bytes, &parts.suffix]);
+        let tag = trailer_tag(self.key.schedule(), self.nonce, &blocks);
+        let codec = self.codec.codec();
+        let mut slices: Vec<Option<Vec<u8>>> = vec![Some(Vec::new()); self.slices.len()];
+        encode_and_write(&tag_block(&tag), 24, &mut slices, codec.as_ref())?;
+        for block in slot_blocks.iter().chain([&parts.nonce, &params_bytes, &parts.suffix]) {
+            encode_and_write(block, 24, &mut slices, codec.as_ref())?;
+        }
+        Ok(Trailer {
+            offset: parts.offset,
+            slices: slices.into_iter().flatten().collect(),
+        })
+    }
+
+    /// Calls `f(bytes_done, bytes_total)` after every chunk
+    pub fn progress(mut self, f: &'a mut dyn FnMut(u64, u64)) -> Self {
+        self.progress = Some(f);
+        self
+    }
+
+    /// Decodes only the given plaintext bytes, cut off at the end of the file. Slices are read
+    /// from the first chunk the range touches to the last; every chunk is authenticated, but
+    /// the CRC32C of the whole file cannot be checked. Fails if the range starts past the end,
+    /// or for version 1, whose chunks have no tags.
+    pub fn range(mut self, range: Range<u64>) -> Result<Self, PhnxError> {
+        if !self.authenticated {
+            return Err(PhnxError::Format(
+                "Slices of version 1 have no chunk tags, decode all of them instead"
+                    .to_string(),
+            ));
+        }
+        if range.start > range.end {
+            return Err(PhnxError::Format(format!(
+                "Range ends at {} before it starts at {}",
+                range.end, range.start
+            )));
+        }
+        if range.start > self.length {
+            return Err(PhnxError::Format(format!(
+                "Range starts at {}, past the end of the {} byte file",
+                range.start, self.length
+            )));
+        }
+        self.range = Some(range.start..range.end.min(self.length));
+        Ok(self)
+    }
+
+    /// Writes chunks that are damaged or fail authentication too, with bytes that cannot be
+    /// recovered overwritten as given. By default output stops before the first such chunk.
+    pub fn keep_damaged(mut self, fill: DamageFill) -> Self {
+        self.keep = Some(fill);
+        self
+    }
+
+    /// Corrects and decrypts chunks on this many worker threads, with slices read on another
+    /// one. The output is the same for any number; 1, the default, uses no threads.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Decodes the slices into output. Each chunk is authenticated before it is written, then
+    /// CRC32C of the result is verified, unless only a range was asked for. Ranges of chunks
+    /// that could not be recovered are listed by PhnxError::Damaged, or by PhnxError::AuthFailed
+    /// if some of them had no uncorrectable codewords to explain the failure.
+    pub fn decode<W: Write>(mut self, mut output: W) -> Result<Report, PhnxError>
+    where
+        R: Send,
+    {
+        let schedule = self.key.schedule();
+        let nonce = self.nonce;
+        let length = self.length;
+        let authenticated = self.authenticated;
+        let params = self.codec;
+        let tag_size = if authenticated { params.slice_bytes(24) } else { 0 };
+        let mut crc32c = Crc32c::new();
+
+        let range = self.range.clone().unwrap_or(0..length);
+        let slices = &mut self.slices;
+        let chunk_count = range.end.div_ceil(CHUNK_SIZE as u64);
+        let mut chunk_index = range.start / CHUNK_SIZE as u64;
+        // Chunks before the range take the same space in every slice, so the first one needed
+        // is found without reading them
+        let mut slice_offset = self.data_offset
+            + chunk_index * (params.slice_bytes(CHUNK_SIZE) + tag_size) as u64;
+        if chunk_index != 0 {
+            for (i, slice) in slices.iter_mut().enumerate() {
+                if let Some(s) = slice {
+                    s.seek(SeekFrom::Start(slice_offset)).map_err(|e| {
+                        PhnxError::io(format!("Error seeking in slice {}", slice_letter(i)), e)
+                    })?;
+                }
+            }
+        }
+        let mut withholding = false;
+        let mut damaged = Vec::new();
+        let mut unauthenticated = Vec::new();
+        pipeline::run(
+            self.threads,
+            || {
+                if chunk_index == chunk_count {
+                    return Ok(None);
+                }
+                let chunk_offset = chunk_index * CHUNK_SIZE as u64;
+                let size = std::cmp::min(length - chunk_offset, CHUNK_SIZE as u64) as usize;
+                let part_size = params.slice_bytes(size) + tag_size;
+                let chunk = ChunkParts {
+                    index: chunk_index,
+                    size,
+                    slice_offset,
+                    parts: read_slice_parts(slices, part_size)?,
+                };
+                chunk_index += 1;
+                slice_offset += part_size as u64;
+                Ok(Some(chunk))
+            },
+            |chunk| decode_chunk(chunk, nonce, schedule, &params, authenticated),
+            |mut chunk| {
+                self.stats.merge(&chunk.stats);
+                for (total, errors) in self.slice_errors.iter_mut().zip(&chunk.slice_errors) {
+                    total.merge(errors);
+                }
+
+                let chunk_offset = chunk.index * CHUNK_SIZE as u64;
+                let plaintext = &mut chunk.plaintext;
+                for r in chunk.damaged {
+                    let start = chunk_offset + r.start as u64;
+                    self.keep
+                        .unwrap_or_default()
+                        .apply(&mut plaintext[r.clone()], start);
+                    add_damage(&mut damaged, start..chunk_offset + r.end as u64);
+                }
+                let chunk_end = chunk_offset + plaintext.len() as u64;
+                if chunk.forged {
+                    add_damage(&mut unauthenticated, chunk_offset..chunk_end);
+                }
+                // Only the part of the chunk within the range is output
+                let start = range.start.saturating_sub(chunk_offset) as usize;
+                let end = (range.end.min(chunk_end) - chunk_offset) as usize;
+                let plaintext = &plaintext[start..end];
+                crc32c.update_slice(plaintext);
+
+                withholding |= chunk.failed && self.keep.is_none();
+                if !withholding {
+                    output
+                        .write_all(plaintext)
+                        .map_err(|e| PhnxError::io("Error writing output", e))?;
+                }
+
+                if let Some(f) = self.progress.as_mut() {
+                    f(range.end.min(chunk_end) - range.start, range.end - range.start);
+                }
+                Ok(())
+            },
+        )?;
+        output
+            .flush()
+            .map_err(|e| PhnxError::io("Error writing output", e))?;
+
+        let report = Report {
+            length: range.end - range.start,
+            crc32c: crc32c.finalize(),
+            stats: self.stats,
+            slice_errors: self.slice_errors,
+            damaged,
+            unauthenticated,
+            metadata: self.metadata,
+        };
+        if !report.unauthenticated.is_empty() {
+            return Err(PhnxError::AuthFailed(Box::new(report)));
+        }
+        if !report.damaged.is_empty() {
+            return Err(PhnxError::Damaged(Box::new(report)));
+        }
+
+        if range == (0..length) && self.expected_crc32c != report.crc32c {
+            return Err(PhnxError::Format(format!(
+                "CRC32C mismatch: expected 0x{:x}, got 0x{:x}",
+                self.expected_crc32c, report.crc32c
+            )));
+        }
+        Ok(report)
+    }
+}
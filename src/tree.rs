@@ -0,0 +1,492 @@
+//! Recursive mode. Every regular file under a directory is encoded into slices next to it, and
+//! the tree itself is described by a manifest: relative paths, sizes, modification times, and
+//! CRC32C of every file, plus directories and symbolic links. The manifest is encrypted and
+//! encoded like any other file, into slices named .phnx_manifest in the top directory.
+//! Restoring goes by the manifest alone, so it knows which files should be there and can tell
+//! which of them failed.
+//!
+//! Names starting with a dot are skipped unless Options::hidden is set. Symbolic links are
+//! never followed, only recorded and recreated. Special files such as sockets, pipes, and
+//! devices are skipped with a warning, as are names that are not valid UTF-8.
+
+use std::fs::{self, File, Metadata};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::codec::MAX_SLICE_COUNT;
+use crate::error::PhnxError;
+use crate::metadata::{time_from_parts, time_to_parts};
+use crate::process::{
+    create_slices, decode_set, decode_slices, encode_slices, file_name, finish_slices,
+    new_encoder, open_slice_set, print_warnings, slice_base_for, slice_base_name, slice_filename,
+    slice_pattern, Options,
+};
+
+/// Base name of the manifest slices in the top directory
+pub const MANIFEST_NAME: &str = ".phnx_manifest";
+
+/// Start of the manifest plaintext, the last digit is its version
+const MANIFEST_MAGIC: &[u8; 16] = b"PHNX manifest 1\n";
+
+const KIND_DIRECTORY: u8 = 1;
+const KIND_FILE: u8 = 2;
+const KIND_SYMLINK: u8 = 3;
+
+/// Seconds written for a modification time the system did not report
+const UNKNOWN_TIME: i64 = i64::MIN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    Directory,
+    /// Regular file, with the length and CRC32C of its contents, and the name its slices go by
+    /// in the same directory, which differs from its own if that is hidden
+    File {
+        size: u64,
+        crc32c: u32,
+        slices: String,
+    },
+    /// Symbolic link, with the path it points to as it was written
+    Symlink {
+        target: String,
+    },
+}
+
+/// One entry of the manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path relative to the top directory, with / between names
+    pub path: String,
+    pub kind: EntryKind,
+    pub modified: Option<SystemTime>,
+}
+
+/// What happened to the files of a tree
+#[derive(Debug, Default)]
+pub struct TreeReport {
+    /// Files encoded or restored
+    pub files: usize,
+    /// Files, directories, and links that could not be, with the reason
+    pub failed: Vec<(String, PhnxError)>,
+}
+
+/// Restores the tree under dir if it has a manifest, otherwise encodes every file in it and
+/// writes one. To encode a restored tree again, remove its manifest first. Slices and files go
+/// where the tree is, so options cannot name an output or output directory.
+pub fn process_tree(
+    dir: &str,
+    password: &[u8],
+    options: &Options,
+) -> Result<TreeReport, PhnxError> {
+    if !Path::new(dir).is_dir() {
+        return Err(PhnxError::Format(format!("{} is not a directory", dir)));
+    }
+    if options.output.is_some() || options.outdir.is_some() {
+        return Err(PhnxError::Format(
+            "Options --output and --outdir do not apply to -r".to_string(),
+        ));
+    }
+    let manifest_base = join(dir, MANIFEST_NAME);
+    if (0..MAX_SLICE_COUNT).any(|i| Path::new(&slice_filename(&manifest_base, i)).exists()) {
+        restore_tree(dir, &manifest_base, password, options)
+    } else {
+        encode_tree(dir, &manifest_base, password, options)
+    }
+}
+
+fn encode_tree(
+    top: &str,
+    manifest_base: &str,
+    password: &[u8],
+    options: &Options,
+) -> Result<TreeReport, PhnxError> {
+    if options.compatibility_mode {
+        return Err(PhnxError::Format(format!(
+            "Cannot encode {} in compatibility mode, recursive mode needs slices",
+            top
+        )));
+    }
+    let mut report = TreeReport::default();
+    let mut found = Vec::new();
+    walk(top, "", options, &mut found, &mut report)?;
+
+    let mut entries = Vec::new();
+    for (path, metadata) in found {
+        let full = join(top, &path);
+        let kind = if metadata.is_dir() {
+            EntryKind::Directory
+        } else if metadata.is_symlink() {
+            let target = match fs::read_link(&full) {
+                Ok(target) => target,
+                Err(e) => {
+                    report
+                        .failed
+                        .push((full.clone(), PhnxError::io("Cannot read link", e)));
+                    continue;
+                }
+            };
+            match target.into_os_string().into_string() {
+                Ok(target) => EntryKind::Symlink { target },
+                Err(_) => {
+                    eprintln!("Skipped {}, link target is not valid UTF-8", full);
+                    continue;
+                }
+            }
+        } else {
+            println!("Processing {}", full);
+            let encoded = slice_base_for(&full, options)
+                .and_then(|base| Ok((encode_slices(&full, &base, password, options)?, base)));
+            match encoded {
+                Ok((encoded, base)) => {
+                    report.files += 1;
+                    EntryKind::File {
+                        size: encoded.length,
+                        crc32c: encoded.crc32c,
+                        slices: file_name(&base).to_string(),
+                    }
+                }
+                Err(e) => {
+                    report.failed.push((full, e));
+                    continue;
+                }
+            }
+        };
+        entries.push(ManifestEntry {
+            path,
+            kind,
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    println!(
+        "Writing manifest {}",
+        slice_pattern(manifest_base, options.codec.slice_count())
+    );
+    let (slices, pending) = create_slices(manifest_base, options)?;
+    new_encoder(password, options)?.encode(Cursor::new(serialize_manifest(&entries)), slices)?;
+    finish_slices(manifest_base, options.codec.slice_count(), pending)?;
+    Ok(report)
+}
+
+/// Collects everything under top/relative worth encoding, sorted by name with every directory
+/// right before what is in it. Subdirectories that cannot be read go to report.
+fn walk(
+    top: &str,
+    relative: &str,
+    options: &Options,
+    found: &mut Vec<(String, Metadata)>,
+    report: &mut TreeReport,
+) -> Result<(), PhnxError> {
+    let dir = join(top, relative);
+    let mut names = Vec::new();
+    let entries =
+        fs::read_dir(&dir).map_err(|e| PhnxError::io(format!("Cannot read {}", dir), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| PhnxError::io(format!("Cannot read {}", dir), e))?;
+        match entry.file_name().into_string() {
+            Ok(name) => names.push(name),
+            Err(name) => eprintln!(
+                "Skipped {}, name is not valid UTF-8",
+                Path::new(&dir).join(name).display()
+            ),
+        }
+    }
+    names.sort();
+
+    for name in names {
+        // Slices of the files and of the manifest are what this produces, not input
+        if slice_base_name(&name).is_some() || (name.starts_with('.') && !options.hidden) {
+            continue;
+        }
+        let path = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        let full = join(top, &path);
+        let metadata = match fs::symlink_metadata(&full) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                report
+                    .failed
+                    .push((full, PhnxError::io("Cannot read metadata", e)));
+                continue;
+            }
+        };
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            found.push((path.clone(), metadata));
+            if let Err(e) = walk(top, &path, options, found, report) {
+                report.failed.push((full, e));
+            }
+        } else if file_type.is_file() || file_type.is_symlink() {
+            found.push((path, metadata));
+        } else {
+            eprintln!("Skipped {}, not a regular file, directory, or link", full);
+        }
+    }
+    Ok(())
+}
+
+fn restore_tree(
+    top: &str,
+    manifest_base: &str,
+    password: &[u8],
+    options: &Options,
+) -> Result<TreeReport, PhnxError> {
+    let (set, _, warnings) = open_slice_set(manifest_base)?;
+    println!(
+        "Reading manifest {}",
+        slice_pattern(manifest_base, set.slices.len())
+    );
+    print_warnings(&warnings);
+    // Files missing from a damaged manifest would go unnoticed, so it is never kept damaged
+    let strict = Options {
+        keep_damaged: None,
+        ..options.clone()
+    };
+    let mut bytes = Vec::new();
+    decode_set(set, password, &strict, |_| Ok(&mut bytes))?;
+    let entries = parse_manifest(&bytes)?;
+
+    let mut report = TreeReport::default();
+    for entry in &entries {
+        let full = join(top, &entry.path);
+        let result = check_no_links_above(top, &entry.path).and_then(|()| match &entry.kind {
+            EntryKind::Directory => fs::create_dir_all(&full)
+                .map_err(|e| PhnxError::io(format!("Cannot create {}", full), e)),
+            EntryKind::File {
+                size,
+                crc32c,
+                slices,
+            } => restore_file(&full, *size, *crc32c, slices, password, options)
+                .map(|()| report.files += 1),
+            EntryKind::Symlink { target } => restore_symlink(&full, target),
+        });
+        if let Err(e) = result {
+            report.failed.push((full, e));
+        }
+    }
+
+    // Files get their times back from their own slices. Creating what is in a directory
+    // changes its time, so directories come last, innermost first.
+    if !options.skip_metadata {
+        for entry in entries.iter().rev() {
+            if entry.kind == EntryKind::Directory {
+                set_modified(&join(top, &entry.path), entry.modified);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Decodes a file from its slices, named as given in the same directory, and puts it in place
+/// only if it matches the manifest
+fn restore_file(
+    path: &str,
+    size: u64,
+    crc32c: u32,
+    slices: &str,
+    password: &[u8],
+    options: &Options,
+) -> Result<(), PhnxError> {
+    let base_filename = Path::new(path).with_file_name(slices);
+    let options = Options {
+        output: Some(path.to_string()),
+        ..options.clone()
+    };
+    // Slices of another file encrypted with the same password decode fine
+    decode_slices(&base_filename.to_string_lossy(), password, &options, |decoded| {
+        if decoded.length != size || decoded.crc32c != crc32c {
+            return Err(PhnxError::Format(format!(
+                "Slices of {} do not match the manifest, not restored",
+                path
+            )));
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Recreates a link unless the same one is already there
+fn restore_symlink(path: &str, target: &str) -> Result<(), PhnxError> {
+    if fs::read_link(path).is_ok_and(|existing| existing == Path::new(target)) {
+        return Ok(());
+    }
+    if fs::symlink_metadata(path).is_ok() {
+        return Err(PhnxError::Format(format!(
+            "{} exists, not replaced with a link to {}",
+            path, target
+        )));
+    }
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, path)
+            .map_err(|e| PhnxError::io(format!("Cannot create link {}", path), e))
+    }
+    #[cfg(not(unix))]
+    {
+        Err(PhnxError::Format(format!(
+            "Cannot create link {} to {} on this system",
+            path, target
+        )))
+    }
+}
+
+/// Sets the modification time of a directory if known, as far as the system allows
+fn set_modified(path: &str, modified: Option<SystemTime>) {
+    if let Some(time) = modified {
+        File::open(path).and_then(|f| f.set_modified(time)).ok();
+    }
+}
+
+fn join(top: &str, relative: &str) -> String {
+    Path::new(top).join(relative).to_string_lossy().into_owned()
+}
+
+/// Manifest plaintext: the magic, then for every entry its kind byte, path, and modification
+/// time, followed by size, CRC32C, and slice name for files or the target for links.
+/// Strings are a 32-bit length and UTF-8 bytes, times are signed seconds and nanoseconds since
+/// the Unix epoch, all numbers little-endian.
+fn serialize_manifest(entries: &[ManifestEntry]) -> Vec<u8> {
+    fn put_string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    let mut out = MANIFEST_MAGIC.to_vec();
+    for entry in entries {
+        out.push(match entry.kind {
+            EntryKind::Directory => KIND_DIRECTORY,
+            EntryKind::File { .. } => KIND_FILE,
+            EntryKind::Symlink { .. } => KIND_SYMLINK,
+        });
+        put_string(&mut out, &entry.path);
+        let (secs, nanos) = entry.modified.map_or((UNKNOWN_TIME, 0), time_to_parts);
+        out.extend_from_slice(&secs.to_le_bytes());
+        out.extend_from_slice(&nanos.to_le_bytes());
+        match &entry.kind {
+            EntryKind::Directory => {}
+            EntryKind::File {
+                size,
+                crc32c,
+                slices,
+            } => {
+                out.extend_from_slice(&size.to_le_bytes());
+                out.extend_from_slice(&crc32c.to_le_bytes());
+                put_string(&mut out, slices);
+            }
+            EntryKind::Symlink { target } => put_string(&mut out, target),
+        }
+    }
+    out
+}
+
+fn parse_manifest(bytes: &[u8]) -> Result<Vec<ManifestEntry>, PhnxError> {
+    let mut reader = ManifestReader { bytes, pos: 0 };
+    if reader.take(MANIFEST_MAGIC.len())? != MANIFEST_MAGIC {
+        return Err(PhnxError::Format(
+            "Not a manifest, or an unsupported version".to_string(),
+        ));
+    }
+    let mut entries = Vec::new();
+    while reader.pos < bytes.len() {
+        let kind = reader.take(1)?[0];
+        let path = reader.string()?;
+        check_relative(&path)?;
+        let secs = i64::from_le_bytes(reader.array()?);
+        let nanos = u32::from_le_bytes(reader.array()?);
+        let kind = match kind {
+            KIND_DIRECTORY => EntryKind::Directory,
+            KIND_FILE => {
+                let size = u64::from_le_bytes(reader.array()?);
+                let crc32c = u32::from_le_bytes(reader.array()?);
+                // Slices are in the same directory as the file
+                let slices = reader.string()?;
+                if slices.contains('/') {
+                    return Err(PhnxError::Format(format!(
+                        "Bad slice name {:?} in manifest",
+                        slices
+                    )));
+                }
+                check_relative(&slices)?;
+                EntryKind::File {
+                    size,
+                    crc32c,
+                    slices,
+                }
+            }
+            KIND_SYMLINK => EntryKind::Symlink {
+                target: reader.string()?,
+            },
+            _ => {
+                return Err(PhnxError::Format(format!(
+                    "Unknown manifest entry {}",
+                    kind
+                )))
+            }
+        };
+        entries.push(ManifestEntry {
+            path,
+            kind,
+            modified: (secs != UNKNOWN_TIME).then(|| time_from_parts(secs, nanos)).flatten(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Refuses paths that could lead out of the top directory
+fn check_relative(path: &str) -> Result<(), PhnxError> {
+    let bad_name =
+        |name: &str| name.is_empty() || name == "." || name == ".." || name.contains(['\\', '\0']);
+    if path.split('/').any(bad_name) {
+        return Err(PhnxError::Format(format!(
+            "Bad path {:?} in manifest",
+            path
+        )));
+    }
+    Ok(())
+}
+
+/// Refuses paths below a link, restored earlier or already there, which could lead anywhere
+fn check_no_links_above(top: &str, path: &str) -> Result<(), PhnxError> {
+    let mut ancestor = PathBuf::from(top);
+    let mut names = path.split('/');
+    names.next_back();
+    for name in names {
+        ancestor.push(name);
+        if fs::symlink_metadata(&ancestor).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(PhnxError::Format(format!(
+                "{} is a link, {} not restored below it",
+                ancestor.display(),
+                path
+            )));
+        }
+    }
+    Ok(())
+}
+
+struct ManifestReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ManifestReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PhnxError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(PhnxError::Format("Manifest is truncated".to_string()));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], PhnxError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn string(&mut self) -> Result<String, PhnxError> {
+        let len = u32::from_le_bytes(self.array()?) as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| PhnxError::Format("Manifest holds a name that is not UTF-8".to_string()))
+    }
+}
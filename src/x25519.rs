@@ -0,0 +1,228 @@
+//! X25519 key agreement (RFC 7748): a Montgomery ladder over Curve25519, with field elements
+//! modulo 2^255 - 19 in five 51-bit limbs.
+
+use crate::error::PhnxError;
+use crate::random;
+
+type Fe = [u64; 5];
+
+const MASK: u64 = (1 << 51) - 1;
+
+/// u-coordinate of the base point, 9
+pub const BASEPOINT: [u8; 32] = {
+    let mut b = [0u8; 32];
+    b[0] = 9;
+    b
+};
+
+/// (A - 2) / 4 for A = 486662
+const A24: Fe = [121665, 0, 0, 0, 0];
+
+fn fe_from_bytes(b: &[u8; 32]) -> Fe {
+    let load = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+    // Bit 255 is ignored, as RFC 7748 asks
+    [
+        load(0) & MASK,
+        (load(6) >> 3) & MASK,
+        (load(12) >> 6) & MASK,
+        (load(19) >> 1) & MASK,
+        (load(24) >> 12) & MASK,
+    ]
+}
+
+/// Carries every limb into the next, the top one back into the lowest times 19
+fn fe_carry(mut a: Fe) -> Fe {
+    for i in 0..4 {
+        a[i + 1] += a[i] >> 51;
+        a[i] &= MASK;
+    }
+    a[0] += 19 * (a[4] >> 51);
+    a[4] &= MASK;
+    a[1] += a[0] >> 51;
+    a[0] &= MASK;
+    a
+}
+
+/// Fully reduced little-endian bytes
+fn fe_to_bytes(a: &Fe) -> [u8; 32] {
+    let mut t = fe_carry(fe_carry(*a));
+    // t is below 2^255 + 19, subtract p once if t + 19 reaches 2^255
+    let mut q = (t[0] + 19) >> 51;
+    for limb in &t[1..] {
+        q = (limb + q) >> 51;
+    }
+    t[0] += 19 * q;
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK;
+    }
+    t[4] &= MASK;
+
+    let mut out = [0u8; 32];
+    let mut acc: u128 = 0;
+    let mut bits = 0;
+    let mut i = 0;
+    for limb in t {
+        acc |= (limb as u128) << bits;
+        bits += 51;
+        while bits >= 8 {
+            out[i] = acc as u8;
+            acc >>= 8;
+            bits -= 8;
+            i += 1;
+        }
+    }
+    out[i] = acc as u8;
+    out
+}
+
+fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3], a[4] + b[4]]
+}
+
+/// a - b, with 4p added so that no limb goes negative
+fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    const FOUR_P0: u64 = 4 * ((1 << 51) - 19);
+    const FOUR_P: u64 = 4 * MASK;
+    fe_carry([
+        a[0] + FOUR_P0 - b[0],
+        a[1] + FOUR_P - b[1],
+        a[2] + FOUR_P - b[2],
+        a[3] + FOUR_P - b[3],
+        a[4] + FOUR_P - b[4],
+    ])
+}
+
+fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |x: u64, y: u64| x as u128 * y as u128;
+    // 2^255 = 19 modulo p, so limbs past the top wrap around times 19
+    let b19 = [0, b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19];
+    let mut r = [
+        m(a[0], b[0]) + m(a[1], b19[4]) + m(a[2], b19[3]) + m(a[3], b19[2]) + m(a[4], b19[1]),
+        m(a[0], b[1]) + m(a[1], b[0]) + m(a[2], b19[4]) + m(a[3], b19[3]) + m(a[4], b19[2]),
+        m(a[0], b[2]) + m(a[1], b[1]) + m(a[2], b[0]) + m(a[3], b19[4]) + m(a[4], b19[3]),
+        m(a[0], b[3]) + m(a[1], b[2]) + m(a[2], b[1]) + m(a[3], b[0]) + m(a[4], b19[4]),
+        m(a[0], b[4]) + m(a[1], b[3]) + m(a[2], b[2]) + m(a[3], b[1]) + m(a[4], b[0]),
+    ];
+    for i in 0..4 {
+        r[i + 1] += r[i] >> 51;
+        r[i] &= MASK as u128;
+    }
+    r[0] += 19 * (r[4] >> 51);
+    r[4] &= MASK as u128;
+    fe_carry([r[0] as u64, r[1] as u64, r[2] as u64, r[3] as u64, r[4] as u64])
+}
+
+/// a^(p - 2), the inverse of a, or 0 for 0
+fn fe_invert(a: &Fe) -> Fe {
+    // p - 2 = 2^255 - 21: every bit from 254 down is set but 4 and 2
+    let mut r = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
+        r = fe_mul(&r, &r);
+        if bit != 4 && bit != 2 {
+            r = fe_mul(&r, a);
+        }
+    }
+    r
+}
+
+/// Swaps a and b if swap is 1, without branching on it
+fn fe_cswap(swap: u64, a: &mut Fe, b: &mut Fe) {
+    let mask = 0u64.wrapping_sub(swap);
+    for i in 0..5 {
+        let t = mask & (a[i] ^ b[i]);
+        a[i] ^= t;
+        b[i] ^= t;
+    }
+}
+
+/// Multiplies the point with u-coordinate u by the scalar, clamped first
+pub fn x25519(scalar: &[u8; 32], u: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+
+    let x1 = fe_from_bytes(u);
+    let mut x2: Fe = [1, 0, 0, 0, 0];
+    let mut z2: Fe = [0; 5];
+    let mut x3 = x1;
+    let mut z3: Fe = [1, 0, 0, 0, 0];
+    let mut swap = 0;
+    for t in (0..255).rev() {
+        let bit = ((k[t / 8] >> (t % 8)) & 1) as u64;
+        swap ^= bit;
+        fe_cswap(swap, &mut x2, &mut x3);
+        fe_cswap(swap, &mut z2, &mut z3);
+        swap = bit;
+
+        let a = fe_add(&x2, &z2);
+        let aa = fe_mul(&a, &a);
+        let b = fe_sub(&x2, &z2);
+        let bb = fe_mul(&b, &b);
+        let e = fe_sub(&aa, &bb);
+        let c = fe_add(&x3, &z3);
+        let d = fe_sub(&x3, &z3);
+        let da = fe_mul(&d, &a);
+        let cb = fe_mul(&c, &b);
+        let sum = fe_add(&da, &cb);
+        x3 = fe_mul(&sum, &sum);
+        let difference = fe_sub(&da, &cb);
+        z3 = fe_mul(&x1, &fe_mul(&difference, &difference));
+        x2 = fe_mul(&aa, &bb);
+        z2 = fe_mul(&e, &fe_add(&aa, &fe_mul(&A24, &e)));
+    }
+    fe_cswap(swap, &mut x2, &mut x3);
+    fe_cswap(swap, &mut z2, &mut z3);
+    fe_to_bytes(&fe_mul(&x2, &fe_invert(&z2)))
+}
+
+/// Random private key, fails if the system has no random source
+pub fn generate_private_key() -> Result<[u8; 32], PhnxError> {
+    let mut private_key = [0u8; 32];
+    random::fill(&mut private_key)?;
+    Ok(private_key)
+}
+
+/// Public key of a private key, which is any 32 random bytes
+pub fn public_key(private_key: &[u8; 32]) -> [u8; 32] {
+    x25519(private_key, &BASEPOINT)
+}
+
+pub fn self_test() -> bool {
+    fn hex(s: &str) -> [u8; 32] {
+        let mut b = [0u8; 32];
+        for (i, byte) in b.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        b
+    }
+
+    // RFC 7748, sections 5.2 and 6.1
+    let scalar = hex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4");
+    let u = hex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c");
+    let expected = hex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552");
+    let alice = hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+    let alice_public = hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+    let bob = hex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+    let bob_public = hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+    let shared = hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+
+    let checks = [
+        (x25519(&scalar, &u), expected),
+        (public_key(&alice), alice_public),
+        (public_key(&bob), bob_public),
+        (x25519(&alice, &bob_public), shared),
+        (x25519(&bob, &alice_public), shared),
+    ];
+    for (observed, expected) in checks {
+        if observed != expected {
+            eprintln!("X25519 self-test failed");
+            eprintln!("Expected {:02x?}", expected);
+            eprintln!("Observed {:02x?}", observed);
+            return false;
+        }
+    }
+
+    true
+}